@@ -0,0 +1,114 @@
+-- Wireshark dissector for NK LEED RS232 frames, as exported by capture_export.
+-- Packets are on link type DLT_USER0 (147), one 6 byte frame each:
+--
+--   | STX | ID | MSB | LSB | BCC | ETX |
+--   | $02 |    |     |     |     | $03 |
+--
+-- BCC is the XOR of bytes 1..4, STX included.
+-- Direction is in the packet flags: inbound = controller to host.
+--
+-- Install: copy to the Wireshark personal plugins folder,
+-- or run: wireshark -X lua_script:docs/leed_dissector.lua capture.pcapng
+
+local leed = Proto("nkleed", "NK LEED RS232")
+
+-- ID table, follows protocol.rs
+local ids = {
+    [0x20] = "Status",
+    [0x21] = "DigOut",
+    [0x31] = "L2_SET",
+    [0x32] = "WEH_SET",
+    [0x33] = "L13_SET",
+    [0x34] = "SCR_SET",
+    [0x35] = "RET_SET_INT",
+    [0x36] = "BEAM_SET_INT",
+    [0x37] = "IFIL_SET1",
+    [0x38] = "EMI_SET",
+    [0x39] = "EMI_MAX",
+    [0x3A] = "DAC_10",
+    [0x41] = "L13_MON",
+    [0x42] = "EMI_MON",
+    [0x43] = "L2_MON",
+    [0x44] = "BEAM_MON",
+    [0x45] = "I0_MON",
+    [0x46] = "RET_MON",
+    [0x47] = "SCR_MON",
+    [0x48] = "IFIL_MON",
+    [0x49] = "WEH_MON",
+}
+
+local status_bits = {
+    [0x01] = "/MON",
+    [0x02] = "SHUTDOWN",
+    [0x04] = "ENABLE",
+    [0x08] = "15V_OK",
+    [0x10] = "15VHV_OK",
+    [0x20] = "SAFETY_SWITCH_OPEN",
+}
+
+local f_stx = ProtoField.uint8("nkleed.stx", "STX", base.HEX)
+local f_id = ProtoField.uint8("nkleed.id", "ID", base.HEX, ids)
+local f_value = ProtoField.uint16("nkleed.value", "Value", base.DEC_HEX)
+local f_bcc = ProtoField.uint8("nkleed.bcc", "BCC", base.HEX)
+local f_bcc_ok = ProtoField.bool("nkleed.bcc_ok", "BCC valid")
+local f_etx = ProtoField.uint8("nkleed.etx", "ETX", base.HEX)
+local f_status = ProtoField.uint8("nkleed.status", "Status", base.HEX)
+local f_leed_int = ProtoField.bool("nkleed.dig_out.leed_int", "LEED/AUGER intern", 8, nil, 0x40)
+local f_beam_int = ProtoField.bool("nkleed.dig_out.beam_int", "BEAM INT/EXT intern", 8, nil, 0x80)
+
+leed.fields = { f_stx, f_id, f_value, f_bcc, f_bcc_ok, f_etx, f_status, f_leed_int, f_beam_int }
+
+local ef_framing = ProtoExpert.new("nkleed.framing", "Bad STX/ETX framing", expert.group.MALFORMED, expert.severity.ERROR)
+local ef_bcc = ProtoExpert.new("nkleed.bcc.bad", "Bad checksum", expert.group.CHECKSUM, expert.severity.ERROR)
+leed.experts = { ef_framing, ef_bcc }
+
+function leed.dissector(buffer, pinfo, tree)
+    if buffer:len() < 6 then
+        return 0
+    end
+
+    pinfo.cols.protocol = "NK LEED"
+
+    local stx = buffer(0, 1):uint()
+    local id = buffer(1, 1):uint()
+    local value = buffer(2, 2):uint()
+    local bcc = buffer(4, 1):uint()
+    local etx = buffer(5, 1):uint()
+    local expected = bit.bxor(stx, id, buffer(2, 1):uint(), buffer(3, 1):uint())
+
+    local subtree = tree:add(leed, buffer(0, 6))
+    subtree:add(f_stx, buffer(0, 1))
+    subtree:add(f_id, buffer(1, 1))
+    local value_item = subtree:add(f_value, buffer(2, 2))
+    subtree:add(f_bcc, buffer(4, 1))
+    local bcc_item = subtree:add(f_bcc_ok, expected == bcc)
+    bcc_item:set_generated()
+    subtree:add(f_etx, buffer(5, 1))
+
+    if stx ~= 0x02 or etx ~= 0x03 then
+        subtree:add_proto_expert_info(ef_framing)
+    end
+    if expected ~= bcc then
+        bcc_item:add_proto_expert_info(ef_bcc)
+    end
+
+    if id == 0x20 then
+        local names = {}
+        for mask, name in pairs(status_bits) do
+            if bit.band(value, mask) ~= 0 then
+                table.insert(names, name)
+            end
+        end
+        value_item:add(f_status, buffer(3, 1)):append_text(" (" .. table.concat(names, ", ") .. ")")
+    elseif id == 0x21 then
+        value_item:add(f_leed_int, buffer(3, 1))
+        value_item:add(f_beam_int, buffer(3, 1))
+    end
+
+    local name = ids[id] or string.format("Unknown $%02X", id)
+    pinfo.cols.info = string.format("%s %d ($%04X)", name, value, value)
+    return 6
+end
+
+local encap = wtap_encaps and wtap_encaps.USER0 or wtap.USER0
+DissectorTable.get("wtap_encap"):add(encap, leed)
@@ -33,10 +33,20 @@ def main():
 
     M.init_scanner(on_scan_start, scan_step_callback)
 
+    # Skips lines that are not JSON, e.g. probes from other device protocols
+    def read_command():
+        msg = port.readline()
+        try:
+            return json.loads(msg)
+        except ValueError:
+            print("Skipping unparsable line:", msg)
+            return None
+
     def check_stop_message():
         if port.inWaiting() > 0:
-            msg = port.readline()
-            command = json.loads(msg)
+            command = read_command()
+            if command is None:
+                return
             tag = command["tag"]
             if tag == "stop_scan":
                 print("Stopping scan")
@@ -44,8 +54,9 @@ def main():
 
     def loop_step():
         print("Waiting for command.")
-        msg = port.readline()
-        command = json.loads(msg)
+        command = read_command()
+        if command is None:
+            return
         print("command:", command)
 
         tag = command["tag"]
@@ -0,0 +1,25 @@
+use leed_controller::common::pcapng::export;
+use std::env;
+use std::io;
+
+// Converts a capture recorded with sniff_ui/leed_ui --record to pcapng.
+// Usage: capture_export <capture> <out.pcapng>
+// Open in Wireshark with docs/leed_dissector.lua loaded.
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: capture_export <capture> <out.pcapng>");
+        std::process::exit(1);
+    }
+
+    let exported = export(&args[1], &args[2])?;
+    println!("Exported {} frames to {}", exported.frames, args[2]);
+    if exported.start_estimated {
+        println!(
+            "Capture has no start time, times assume it ended \
+             when the file was last modified"
+        );
+    }
+    Ok(())
+}
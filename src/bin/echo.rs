@@ -1,8 +1,10 @@
 
-const BAUD_RATE: u32 = 38400;
+use std::env;
+use std::io;
 use std::time::Duration;
 
-use leed_controller::common::protocol::Message;
+use leed_controller::common::protocol::{FrameDecoder, Message};
+use leed_controller::common::transport;
 
 fn main() {
     if let Some(port_name) = get_port() {
@@ -12,7 +14,13 @@ fn main() {
     println!("Exiting.");
 }
 
+// Usage: echo [address]
+// Address is a serial port or "tcp://host:port", default first available port.
 fn get_port() -> Option<String> {
+    if let Some(address) = env::args().nth(1) {
+        return Some(address);
+    }
+
     let ports = serialport::available_ports().expect("Cannot enumerate available ports.");
     let selected_port = ports.first();
 
@@ -23,19 +31,20 @@ fn get_port() -> Option<String> {
     Some(selected_port?.port_name.clone())
 }
 
-fn sniff(port_name: &str) -> Result<(), serialport::Error> {
-    let mut port = serialport::new(port_name, BAUD_RATE).open()?;
-    port.set_timeout(Duration::from_secs(60))?;
+fn sniff(port_name: &str) -> io::Result<()> {
+    let mut port = transport::open(port_name, Duration::from_secs(60))?;
 
-    let mut buf: [u8; 6] = [0; 6];
+    let mut decoder = FrameDecoder::new();
+    let mut buf: [u8; 64] = [0; 64];
     loop {
-        port.read_exact(&mut buf)?;
-        port.write_all(&buf)?;
-        // println!("Message: {:02X?}", buf);
-        if let Some(msg) = Message::from_bytes(&buf) {
-            println!("Message: {:?}", msg);
-        } else {
-            println!("Unhandled message: {:02X?}", buf);
+        let count = port.read(&mut buf)?;
+        for frame in decoder.decode(&buf[..count]) {
+            port.write_all(&frame)?;
+            // println!("Message: {:02X?}", frame);
+            match Message::from_bytes(&frame) {
+                Ok(msg) => println!("Message: {:?}", msg),
+                Err(err) => println!("Unhandled message: {:02X?} ({})", frame, err),
+            }
         }
     }
 }
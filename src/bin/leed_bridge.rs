@@ -0,0 +1,28 @@
+use leed_controller::common::bridge::Bridge;
+use leed_controller::common::discovery::{resolve, DeviceKind, AUTO};
+use std::env;
+use std::io;
+
+// Shares the LEED controller serial port over TCP, see common::bridge.
+// Usage: leed_bridge [port] [control address] [observe address]
+// Port is a port spec as taken by discovery::resolve, default is to probe.
+// leed_ui then connects with tcp://<control address>.
+
+const CONTROL_ADDRESS: &str = "127.0.0.1:7100";
+const OBSERVE_ADDRESS: &str = "127.0.0.1:7101";
+
+fn main() -> io::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let port_spec = env::args().nth(1).unwrap_or_else(|| AUTO.to_string());
+    let control_address = env::args()
+        .nth(2)
+        .unwrap_or_else(|| CONTROL_ADDRESS.to_string());
+    let observe_address = env::args()
+        .nth(3)
+        .unwrap_or_else(|| OBSERVE_ADDRESS.to_string());
+
+    let port = resolve(&port_spec, DeviceKind::Leed)?;
+    let mut bridge = Bridge::new(&port, &control_address, &observe_address)?;
+    bridge.run()
+}
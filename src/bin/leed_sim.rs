@@ -0,0 +1,61 @@
+use leed_controller::common::simulator::{serve, Simulator};
+use leed_controller::common::transport::{SerialTransport, TcpTransport};
+use serialport::{SerialPort, TTYPort};
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Simulated LEED controller.
+// Usage: leed_sim [tcp://host:port]
+// Without an address, a pseudo-terminal is created and its path printed,
+// which can then be passed to leed_ui.
+
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+fn main() -> io::Result<()> {
+    env_logger::init();
+
+    let simulator = Arc::new(Mutex::new(Simulator::new()));
+
+    match env::args().nth(1) {
+        Some(address) => serve_tcp(simulator, &address),
+        None => serve_pty(simulator),
+    }
+}
+
+fn serve_pty(simulator: Arc<Mutex<Simulator>>) -> io::Result<()> {
+    let (mut master, slave) = TTYPort::pair()?;
+    master.set_timeout(READ_TIMEOUT)?;
+
+    println!(
+        "Simulated controller on: {}",
+        slave.name().unwrap_or_default()
+    );
+
+    let handle = serve(
+        simulator,
+        Box::new(SerialTransport::from_port(Box::new(master))),
+    );
+    handle.join().expect("Simulator thread panicked");
+
+    // Keep the slave end open while serving
+    drop(slave);
+    Ok(())
+}
+
+fn serve_tcp(simulator: Arc<Mutex<Simulator>>, address: &str) -> io::Result<()> {
+    let host = address.strip_prefix("tcp://").unwrap_or(address);
+    let listener = TcpListener::bind(host)?;
+    println!("Simulated controller on: tcp://{}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let link = TcpTransport::from_stream(stream?, READ_TIMEOUT)?;
+        serve(simulator.clone(), Box::new(link))
+            .join()
+            .expect("Simulator thread panicked");
+    }
+
+    Ok(())
+}
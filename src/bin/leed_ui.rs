@@ -1,12 +1,25 @@
-use common::leed_controller::{Adjustment, LEEDController};
-use common::sniffer::monitor;
+use common::args::take_option;
+use common::capture::{self, Recorder};
+use common::discovery::{resolve, DeviceKind, AUTO};
+use common::keepalive::{Keepalive, KeepaliveConfig, KeepaliveStats};
+use common::leed_controller::{Adjustment, ControlValue, LEEDController, StartupStep};
+use common::preset::PresetLibrary;
+use common::settings_file::SettingsFile;
+use common::simulator::{serve, Simulator};
+use common::sniffer::{monitor_transport, monitor_with_keepalive, ConnectionState};
+use common::transaction::{RetryPolicy, Transactions};
+use common::transport::ChannelTransport;
 use leed_controller::common;
+use leed_controller::common::protocol::Monitor;
 use leed_controller::common::tui_log::{LogWidget, LogWidgetState, TuiLogger};
-use log::{error, info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use std::collections::VecDeque;
+use std::env;
+use std::fmt::Display;
 use std::io::{self, stdout};
-use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crossterm::{
     event::{self, Event, KeyCode},
@@ -19,7 +32,23 @@ use ratatui::{
     widgets::{Block, Borders, List},
 };
 
-const LEED_PORT: &str = "/dev/ttyUSB0";
+const SIM_ADDRESS: &str = "sim";
+// Bounds the shutdown run after a UI failure, long enough for the filament
+// and screen ramps from their maximum
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(120);
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+// Usage: leed_ui [--record file] [--settings file] [--presets dir] [address]
+// Address is a serial port, "tcp://host:port" or a port spec as taken by
+// discovery::resolve. Default is to probe the serial ports for the controller.
+// "sim" runs against an in-process simulated controller.
+// --settings loads a settings file as the startup targets, 'w' saves the
+// current targets back to it. Without it 'w' saves nothing, so the settings
+// file tracked in the repo is not overwritten by accident.
+// 'p' opens the preset picker, with the builtin presets and one per file in
+// the --presets directory.
+// ':' opens a command line setting a target in physical units, with the
+// names of the settings file: "beam 120 eV", "screen 6500 V", "lens1 gain 2.0".
 
 fn main() -> io::Result<()> {
     let mut ui = UIState::new();
@@ -29,64 +58,183 @@ fn main() -> io::Result<()> {
     let (leed_send, leed_recv) = mpsc::channel();
     let (leed_listener, leed_responses) = mpsc::channel();
 
-    // TODO: Gracefully exit when thread exits
-    let leed_monitor_handle = monitor(LEED_PORT, vec![leed_listener], leed_recv);
-    if leed_monitor_handle.is_err() {
-        error!("LEED communication init failed!");
+    let keepalive = Keepalive::new(KeepaliveConfig::default());
+    ui.keepalive_stats = keepalive.stats();
+
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let record_path = take_option(&mut args, "--record");
+    let settings_path = take_option(&mut args, "--settings");
+    if let Some(dir) = take_option(&mut args, "--presets") {
+        match PresetLibrary::load_dir(&dir) {
+            Ok(presets) => ui.presets = presets,
+            Err(err) => error!("Could not load presets from {}: {}", dir, err),
+        }
+    }
+    let leed_spec = args.first().cloned().unwrap_or_else(|| AUTO.to_string());
+    let leed_monitor_handle = if leed_spec == SIM_ADDRESS {
+        let (host_end, sim_end) = ChannelTransport::pair(Duration::from_millis(10));
+        serve(Arc::new(Mutex::new(Simulator::new())), Box::new(sim_end));
+        Ok(monitor_transport(
+            Box::new(host_end),
+            vec![leed_listener],
+            leed_recv,
+            Some(keepalive),
+        ))
+    } else {
+        resolve(&leed_spec, DeviceKind::Leed).and_then(|leed_port| {
+            info!("LEED controller on {}", leed_port);
+            monitor_with_keepalive(&leed_port, vec![leed_listener], leed_recv, keepalive)
+        })
+    };
+    match &leed_monitor_handle {
+        Ok(handle) => {
+            ui.connection = handle.state.clone();
+            if let Some(path) = record_path {
+                let recorder = Recorder::create(path)?;
+                handle.record(
+                    Arc::new(Mutex::new(recorder)),
+                    capture::Direction::ControllerToHost,
+                );
+            }
+        }
+        Err(err) => {
+            error!("LEED communication init failed! {}", err);
+            ui.connection = Arc::new(Mutex::new(ConnectionState::Failed));
+        }
     }
 
     let mut controller = LEEDController::new();
+    if let Some(path) = settings_path {
+        if let Err(err) = controller.load_settings_file(&path) {
+            error!("Could not load settings from {}: {}", path, err);
+        }
+        ui.settings_path = Some(path);
+    }
+    let mut link = Transactions::new(leed_send, leed_responses, RetryPolicy::default());
 
     enable_raw_mode()?;
     stdout().execute(EnterAlternateScreen)?;
     let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
 
-    let loop_result = ui_loop(
-        &mut terminal,
-        &mut controller,
-        &mut ui,
-        leed_send,
-        leed_responses,
-    );
+    let loop_result = ui_loop(&mut terminal, &mut controller, &mut ui, &mut link);
 
-    controller.graceful_exit();
+    // Restore the terminal first, the shutdown below has to run either way
+    let restored =
+        disable_raw_mode().and_then(|_| stdout().execute(LeaveAlternateScreen).map(|_| ()));
 
     if let Err(error) = loop_result {
         // Print, since it seems logger does not write
         // to stdout after raw mode has been entered.
-        print!("UI crashed: {:?}", error);
+        eprintln!("UI crashed: {:?}", error);
+        shut_down_without_ui(&mut controller, &ui, &mut link);
     }
 
-    disable_raw_mode()?;
-    stdout().execute(LeaveAlternateScreen)?;
+    restored
+}
 
-    todo!("Make sure filament is ramped down.");
-    // Ok(())
+// The outputs must not be left live when the UI fails, so the shutdown
+// sequence keeps running without it, bounded by SHUTDOWN_TIMEOUT.
+fn shut_down_without_ui(controller: &mut LEEDController, ui: &UIState, link: &mut Transactions) {
+    controller.graceful_exit();
+    eprintln!("Shutting down the LEED controller...");
+    let start = Instant::now();
+    while !controller.shutdown_complete() {
+        if start.elapsed() > SHUTDOWN_TIMEOUT {
+            eprintln!(
+                "ERROR: shutdown not completed after {:?}, waiting for {}. \
+                 Outputs may still be live, check the controller!",
+                SHUTDOWN_TIMEOUT,
+                controller
+                    .shutdown_waiting_for()
+                    .unwrap_or_else(|| "nothing".to_string())
+            );
+            return;
+        }
+        if let Ok(connection) = ui.connection.lock() {
+            controller.set_connection(*connection);
+        }
+        controller.update(link, |_| {});
+        thread::sleep(SHUTDOWN_POLL);
+    }
+    eprintln!("Shutdown complete");
 }
 
 fn ui_loop<B: Backend>(
     terminal: &mut Terminal<B>,
     controller: &mut LEEDController,
     ui: &mut UIState,
-    leed_send: Sender<[u8; 6]>,
-    leed_responses: Receiver<[u8; 6]>,
+    link: &mut Transactions,
 ) -> io::Result<()> {
-    while handle_ui_events(controller)? {
-        controller.update(&leed_send, &leed_responses, |leed_message| {
+    while handle_ui_events(controller, ui)? {
+        if let Ok(connection) = ui.connection.lock() {
+            controller.set_connection(*connection);
+        }
+        controller.update(link, |leed_message| {
             ui.leed_messages.push_front(format!("{:?}", leed_message));
         });
         ui.update();
         terminal.draw(|frame| {
-            render_ui(frame, controller, ui);
+            render_ui(frame, controller, link, ui);
         })?;
+
+        if controller.shutdown_step().is_some() && controller.shutdown_complete() {
+            break;
+        }
     }
 
     Ok(())
 }
 
-fn handle_ui_events(controller: &mut LEEDController) -> io::Result<bool> {
+fn handle_ui_events(controller: &mut LEEDController, ui: &mut UIState) -> io::Result<bool> {
     let poll_time = std::time::Duration::from_millis(50);
     let mut should_continue = true;
+    let shutting_down = controller.shutdown_step().is_some();
+
+    if shutting_down {
+        // Only a forced exit is possible once shutdown has started
+        if event::poll(poll_time)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == event::KeyEventKind::Press && key.code == KeyCode::Char('Q') {
+                    warn!("Forced exit, shutdown not completed");
+                    should_continue = false;
+                }
+            }
+        }
+        return Ok(should_continue);
+    }
+
+    if let Some(command) = &mut ui.command {
+        if event::poll(poll_time)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == event::KeyEventKind::Press {
+                    match key.code {
+                        KeyCode::Char(c) => command.push(c),
+                        KeyCode::Backspace => {
+                            command.pop();
+                        }
+                        KeyCode::Enter => {
+                            run_command(controller, command);
+                            ui.command = None;
+                        }
+                        KeyCode::Esc => ui.command = None,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        return Ok(should_continue);
+    }
+
+    if let Some(selected) = ui.preset_picker {
+        if event::poll(poll_time)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == event::KeyEventKind::Press {
+                    handle_preset_picker(controller, ui, selected, key.code);
+                }
+            }
+        }
+        return Ok(should_continue);
+    }
 
     let controls = &mut controller.settings;
     let control_inputs = [
@@ -95,16 +243,51 @@ fn handle_ui_events(controller: &mut LEEDController) -> io::Result<bool> {
         ('d', 'c', &mut controls.emission),
         ('f', 'v', &mut controls.filament),
         ('g', 'b', &mut controls.screen),
-        ('h', 'n', &mut controls.lens1_3),
-        ('j', 'm', &mut controls.lens2),
-        ('k', ',', &mut controls.suppressor),
     ];
 
     if event::poll(poll_time)? {
         if let Event::Key(key) = event::read()? {
             if key.kind == event::KeyEventKind::Press {
                 match key.code {
-                    KeyCode::Char('q') => should_continue = false,
+                    KeyCode::Char('q') => {
+                        info!("Shutting down, exit follows once complete. Q forces exit");
+                        controller.graceful_exit();
+                    }
+                    KeyCode::Char('i') => {
+                        info!("Toggle BEAM INT/EXT");
+                        controls.dig_out.toggle_beam_internal()
+                    }
+                    KeyCode::Char('o') => {
+                        info!("Toggle LEED/AUGER");
+                        controls.dig_out.toggle_leed_internal()
+                    }
+                    KeyCode::Char('r') => {
+                        if matches!(controller.startup_step(), StartupStep::Failed(_)) {
+                            controller.restart_startup();
+                        }
+                    }
+                    KeyCode::Char('w') => match &ui.settings_path {
+                        Some(path) => {
+                            if let Err(err) = controller.save_settings_file(path) {
+                                error!("Could not save settings to {}: {}", path, err);
+                            }
+                        }
+                        None => warn!("Not saving settings, start with --settings file"),
+                    },
+                    KeyCode::Char('p') => ui.preset_picker = Some(0),
+                    KeyCode::Char(':') => ui.command = Some(String::new()),
+                    KeyCode::Char('k') => controls.suppressor.adjust(Adjustment::Up),
+                    KeyCode::Char(',') => controls.suppressor.adjust(Adjustment::Down),
+                    KeyCode::Char('h') => controls.lens1_3.adjust_gain(Adjustment::Up),
+                    KeyCode::Char('n') => controls.lens1_3.adjust_gain(Adjustment::Down),
+                    KeyCode::Char('j') => controls.lens2.adjust_gain(Adjustment::Up),
+                    KeyCode::Char('m') => controls.lens2.adjust_gain(Adjustment::Down),
+                    KeyCode::Char('l') => controls.lens1_3.adjust_offset(Adjustment::Up),
+                    KeyCode::Char('.') => controls.lens1_3.adjust_offset(Adjustment::Down),
+                    KeyCode::Char(';') => controls.lens2.adjust_offset(Adjustment::Up),
+                    KeyCode::Char('/') => controls.lens2.adjust_offset(Adjustment::Down),
+                    KeyCode::Char('[') => adjust_ramp_rate(&mut controls.filament, 0.5),
+                    KeyCode::Char(']') => adjust_ramp_rate(&mut controls.filament, 2.0),
                     _ => {
                         for (up, down, control) in control_inputs {
                             if key.code == KeyCode::Char(up) {
@@ -124,7 +307,71 @@ fn handle_ui_events(controller: &mut LEEDController) -> io::Result<bool> {
     Ok(should_continue)
 }
 
-fn render_ui(frame: &mut Frame, controller: &LEEDController, state: &UIState) {
+// "beam 120 eV": the name is everything before the value
+fn run_command(controller: &mut LEEDController, command: &str) {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let value_at = words.iter().position(|word| {
+        word.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+' || c == '.')
+    });
+    let Some(value_at) = value_at.filter(|index| *index > 0) else {
+        error!("Expected name and value, like: beam 120 eV");
+        return;
+    };
+
+    let line = format!(
+        "{}: {}",
+        words[..value_at].join(" "),
+        words[value_at..].join(" ")
+    );
+    let file = match SettingsFile::parse(&line) {
+        Ok(file) => file,
+        Err(err) => {
+            error!("{}: {}", command, err);
+            return;
+        }
+    };
+
+    for (name, quantity) in file.entries() {
+        let unit = quantity.unit.as_deref().unwrap_or_default();
+        match controller.set_target(name, quantity.value, unit) {
+            Ok(()) => info!("{}: {}", name, quantity),
+            Err(err) => error!("{}", err),
+        }
+    }
+}
+
+// Up/Down choose, Enter moves to the preset, Esc closes the picker
+fn handle_preset_picker(
+    controller: &mut LEEDController,
+    ui: &mut UIState,
+    selected: usize,
+    code: KeyCode,
+) {
+    let count = ui.presets.presets().len();
+    match code {
+        KeyCode::Up => ui.preset_picker = Some(selected.saturating_sub(1)),
+        KeyCode::Down => ui.preset_picker = Some((selected + 1).min(count.saturating_sub(1))),
+        KeyCode::Enter => {
+            ui.preset_picker = None;
+            if let Some(preset) = ui.presets.presets().get(selected) {
+                if let Err(err) = controller.select_preset(preset) {
+                    error!("Preset {} rejected: {}", preset.name, err);
+                }
+            }
+        }
+        KeyCode::Esc => ui.preset_picker = None,
+        _ => {}
+    }
+}
+
+fn adjust_ramp_rate(control: &mut ControlValue, factor: f32) {
+    if let Some(ramp) = control.ramp() {
+        control.set_ramp_rate(ramp.rate * factor);
+        info!("{} ramp rate: {}", control.name, ramp.rate * factor);
+    }
+}
+
+fn render_ui(frame: &mut Frame, controller: &LEEDController, link: &Transactions, state: &UIState) {
     let main_layout = Layout::new(
         Direction::Vertical,
         [
@@ -147,8 +394,15 @@ fn render_ui(frame: &mut Frame, controller: &LEEDController, state: &UIState) {
     )
     .split(main_layout[2]);
 
+    let status = match &state.command {
+        Some(command) => format!(":{}", command).into(),
+        None => status_title(controller),
+    };
     frame.render_widget(
-        Block::new().borders(Borders::TOP).title("LEED"),
+        Block::new()
+            .borders(Borders::TOP)
+            .title("LEED")
+            .title(status),
         main_layout[0],
     );
 
@@ -170,7 +424,97 @@ fn render_ui(frame: &mut Frame, controller: &LEEDController, state: &UIState) {
         state.leed_messages.clone(), // TODO: Avoid clone?
     );
 
-    render_controller(frame, controller_layout, controller);
+    render_controller(frame, controller_layout, controller, link);
+
+    if let Ok(stats) = state.keepalive_stats.lock() {
+        let text = format!(
+            "Keepalive: sent {}, max gap {:?}, alarms {}",
+            stats.keepalives_sent, stats.max_gap, stats.alarms
+        );
+        let text = if stats.alarms > 0 {
+            text.red()
+        } else {
+            text.into()
+        };
+        frame.render_widget(Block::new().title(text), top_horiz[1]);
+    }
+
+    render_presets(frame, edge_inset(&top_horiz[1], 1), controller, state);
+}
+
+fn render_presets(frame: &mut Frame, area: Rect, controller: &LEEDController, state: &UIState) {
+    let active = controller.transition().map(|(name, _)| name);
+    let items: Vec<Line> = state
+        .presets
+        .presets()
+        .iter()
+        .enumerate()
+        .map(|(index, preset)| {
+            let line = if Some(preset.name.as_str()) == active {
+                Line::from(format!("{} (changing)", preset.name).yellow())
+            } else {
+                Line::from(preset.name.clone())
+            };
+            if state.preset_picker == Some(index) {
+                line.patch_style(Style::new().reversed())
+            } else {
+                line
+            }
+        })
+        .collect();
+
+    let title = match state.preset_picker {
+        Some(_) => "Presets [Up/Down, Enter selects, Esc closes]",
+        None => "Presets [p]",
+    };
+    let list = List::new(items).block(Block::default().title(title.blue()).borders(Borders::ALL));
+    frame.render_widget(list, area);
+}
+
+fn status_title(controller: &LEEDController) -> Line<'static> {
+    // Shutdown stalls while the link is down, say so rather than just the link state
+    if let Some(step) = controller.shutdown_step() {
+        return match controller.shutdown_waiting_for() {
+            Some(reason) => format!("Shutdown: {}, waiting for {} (Q forces exit)", step, reason),
+            None => format!("Shutdown: {}", step),
+        }
+        .yellow()
+        .into();
+    }
+
+    match controller.connection {
+        ConnectionState::Connected => {}
+        ConnectionState::Reconnecting { .. } => {
+            return format!("Link: {}", controller.connection).yellow().into()
+        }
+        ConnectionState::Failed => return "Link: failed".red().into(),
+    }
+
+    match controller.startup_step() {
+        StartupStep::Running => {}
+        StartupStep::Failed(failure) => {
+            return format!("Startup failed: {} (r retries)", failure)
+                .red()
+                .into()
+        }
+        step => return format!("Startup: {}", step).yellow().into(),
+    }
+
+    if let Some((preset, step)) = controller.transition() {
+        return format!("Preset {}: {}", preset, step).yellow().into();
+    }
+
+    match &controller.status {
+        None => "Status: unknown".yellow().into(),
+        Some(status) => {
+            let faults = status.faults();
+            if faults.is_empty() {
+                "Status: OK".green().into()
+            } else {
+                format!("Status: {}", faults.join(", ")).red().into()
+            }
+        }
+    }
 }
 
 fn render_messages<T>(frame: &mut Frame, area: Rect, title: &str, messages: T)
@@ -183,30 +527,57 @@ where
     frame.render_widget(list, area);
 }
 
-fn render_controller(frame: &mut Frame, area: Rect, c: &LEEDController) {
+fn render_controller(frame: &mut Frame, area: Rect, c: &LEEDController, link: &Transactions) {
     let title = "Controls";
     let mut controls_content = Vec::from(
         [
-            ("[a/z] Beam Energy", &c.settings.beam_energy),
+            ("[a/z] Beam Energy", &c.settings.beam_energy as &dyn Display),
             ("[s/x] Wehnheit", &c.settings.wehnheit),
             ("[d/c] Emission", &c.settings.emission),
             ("[f/v] Filament", &c.settings.filament),
             ("[g/b] Screen", &c.settings.screen),
-            ("[h/n] Lens 1/3 Gain", &c.settings.lens1_3),
-            ("j/m] Lens 2 Gain", &c.settings.lens2),
+            ("[h/n l/.] Lens 1/3", &c.settings.lens1_3),
+            ("[j/m ;//] Lens 2", &c.settings.lens2),
             ("[k/,] Suppressor", &c.settings.suppressor),
         ]
         .map(|(title, value)| format!("{}: {}", title, value)),
     );
 
+    controls_content.push(format!("[i/o] BEAM/LEED int/ext: {}", c.settings.dig_out));
+
     controls_content.extend(
         [
-            ("Beam current", c.currents.beam),
-            ("Emission current", c.currents.emission),
-            ("Filament current", c.currents.filament),
+            ("Beam current", Monitor::I0_MON),
+            ("Emission current", Monitor::EMI_MON),
+            ("Filament current", Monitor::IFIL_MON),
+            ("Beam energy mon", Monitor::BEAM_MON),
+            ("Wehnheit mon", Monitor::WEH_MON),
+            ("Screen mon", Monitor::SCR_MON),
+            ("Lens 1/3 mon", Monitor::L13_MON),
+            ("Lens 2 mon", Monitor::L2_MON),
+            ("Suppressor mon", Monitor::RET_MON),
         ]
-        .map(|(title, value)| format!("{}: {}", title, value)),
+        .map(|(title, monitor)| format!("{}: {}", title, c.monitor_reading(monitor))),
     );
+    if let Some(percentage) = c.suppressor_reading_percentage() {
+        controls_content.push(format!(
+            "Suppressor mon: {:.1}% of beam energy mon",
+            percentage
+        ));
+    }
+
+    controls_content.push(format!("Protocol errors: {}", c.errors));
+
+    let stats = link.stats();
+    controls_content.push(format!(
+        "Link: replies: {}, retries: {}, timeouts: {}, unsolicited: {}, latency: {:?} (max {:?})",
+        stats.replies,
+        stats.retries,
+        stats.timeouts,
+        stats.unsolicited,
+        stats.last_latency.unwrap_or_default(),
+        stats.max_latency
+    ));
 
     let list = List::new(controls_content)
         .block(Block::default().title(title.red()).borders(Borders::ALL));
@@ -272,6 +643,12 @@ fn render_test_plot(frame: &mut Frame, area: Rect) {
 struct UIState {
     leed_messages: VecDeque<String>,
     log_state: Arc<Mutex<LogWidgetState>>,
+    keepalive_stats: Arc<Mutex<KeepaliveStats>>,
+    connection: Arc<Mutex<ConnectionState>>,
+    settings_path: Option<String>, // Written by 'w'
+    presets: PresetLibrary,
+    preset_picker: Option<usize>, // Selected preset while the picker is open
+    command: Option<String>,      // Typed so far while the command line is open
 }
 
 impl UIState {
@@ -279,6 +656,12 @@ impl UIState {
         Self {
             leed_messages: VecDeque::with_capacity(20),
             log_state: Arc::new(Mutex::new(LogWidgetState::default())),
+            keepalive_stats: Arc::new(Mutex::new(KeepaliveStats::default())),
+            connection: Arc::new(Mutex::new(ConnectionState::Connected)),
+            settings_path: None,
+            presets: PresetLibrary::builtin(),
+            preset_picker: None,
+            command: None,
         }
     }
 
@@ -1,7 +1,9 @@
+use leed_controller::common::discovery::{resolve, DeviceKind, AUTO};
 use leed_controller::common::tui_log::{LogWidget, LogWidgetState, TuiLogger};
 use leed_controller::scanner::Scanner;
 use log::LevelFilter;
 use std::collections::VecDeque;
+use std::env;
 use std::io::{self, stdout};
 use std::sync::{Arc, Mutex};
 
@@ -17,13 +19,17 @@ use ratatui::{
     widgets::{canvas::*, *},
 };
 
-const MOTORS_PORT: &str = "/dev/ttyUSB1";
+// Usage: scanner_ui [address]
+// Address is a serial port, "tcp://host:port" or a port spec as taken by
+// discovery::resolve. Default is to probe the serial ports for the motors server.
 
 fn main() -> io::Result<()> {
     let mut ui = UIState::new();
     TuiLogger::init(LevelFilter::Info, ui.log_state.clone()).expect("Logger init failed");
 
-    let mut scanner = Scanner::new(MOTORS_PORT).expect("Scanner init failed");
+    let motors_spec = env::args().nth(1).unwrap_or_else(|| AUTO.to_string());
+    let motors_port = resolve(&motors_spec, DeviceKind::Motors).expect("Motors server not found");
+    let mut scanner = Scanner::new(&motors_port).expect("Scanner init failed");
 
     enable_raw_mode()?;
     stdout().execute(EnterAlternateScreen)?;
@@ -1,9 +1,13 @@
+use leed_controller::common::args::take_option;
+use leed_controller::common::capture::{self, replay, Recorder};
+use leed_controller::common::discovery::{resolve, resolve_pinned, DeviceKind, AUTO};
 use leed_controller::common::leed_controller::LEEDController;
-use leed_controller::common::protocol::{Message, Tag};
-use leed_controller::common::sniffer::monitor;
+use leed_controller::common::protocol::{ErrorCounts, Message, Monitor, Tag};
+use leed_controller::common::sniffer::{monitor, ConnectionState};
 use std::collections::VecDeque;
+use std::env;
 use std::io::{self, stdout};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 use crossterm::{
@@ -14,11 +18,16 @@ use crossterm::{
 use ratatui::{prelude::*, widgets::*};
 
 const MAX_CAP: usize = 100;
+const SOFT_PORT: &str = "/dev/ttyUSB0";
 const DUMMY_REPEATER: bool = true;
 
 struct Counters {
     leed: i32,
     soft: i32,
+    leed_errors: ErrorCounts,
+    soft_errors: ErrorCounts,
+    leed_link: ConnectionState,
+    soft_link: ConnectionState,
 }
 
 fn main() -> io::Result<()> {
@@ -26,22 +35,66 @@ fn main() -> io::Result<()> {
 
     let mut software_messages: VecDeque<String> = VecDeque::with_capacity(20);
     let mut leed_messages: VecDeque<String> = VecDeque::with_capacity(20);
-    let mut counters = Counters { soft: 0, leed: 0 };
+    let mut counters = Counters {
+        soft: 0,
+        leed: 0,
+        leed_errors: ErrorCounts::default(),
+        soft_errors: ErrorCounts::default(),
+        leed_link: ConnectionState::Connected,
+        soft_link: ConnectionState::Connected,
+    };
 
     let (soft_send, soft_recv) = mpsc::channel();
     let (leed_send, leed_recv) = mpsc::channel();
     let (soft_listen_in, soft_listen_out) = mpsc::channel();
     let (leed_listen_in, leed_listen_out) = mpsc::channel();
 
-    let soft_port = "/dev/ttyUSB0";
-    let leed_port = "/dev/ttyUSB1";
-    monitor(soft_port, vec![leed_send, soft_listen_in], soft_recv).unwrap();
-
-    if DUMMY_REPEATER {
-        echo_messages(soft_send, leed_listen_in, leed_recv);
+    // Usage: sniff_ui [--record file] [soft address] [leed address]
+    //        sniff_ui --replay file
+    // Addresses are serial ports, "tcp://host:port" or port specs as taken by
+    // discovery::resolve. The LEED controller is probed for by default,
+    // the software side cannot be probed since it is a master itself.
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let record_path = take_option(&mut args, "--record");
+    let replay_path = take_option(&mut args, "--replay");
+
+    let (soft_state, leed_state) = if let Some(path) = replay_path {
+        replay(path, 1.0, vec![soft_listen_in], vec![leed_listen_in])?;
+        (
+            Arc::new(Mutex::new(ConnectionState::Connected)),
+            Arc::new(Mutex::new(ConnectionState::Connected)),
+        )
     } else {
-        monitor(leed_port, vec![soft_send, leed_listen_in], leed_recv).unwrap();
-    }
+        let soft_spec = args
+            .first()
+            .cloned()
+            .unwrap_or_else(|| SOFT_PORT.to_string());
+        let soft_port = resolve_pinned(&soft_spec).expect("Software port not found");
+        let soft_monitor = monitor(&soft_port, vec![leed_send, soft_listen_in], soft_recv).unwrap();
+
+        // All traffic passes the software side, so recording it is enough
+        if let Some(path) = record_path {
+            let recorder = Recorder::create(path)?;
+            soft_monitor.record(
+                Arc::new(Mutex::new(recorder)),
+                capture::Direction::HostToController,
+            );
+        }
+
+        let leed_state = if DUMMY_REPEATER {
+            echo_messages(soft_send, leed_listen_in, leed_recv);
+            Arc::new(Mutex::new(ConnectionState::Connected))
+        } else {
+            let leed_spec = args.get(1).cloned().unwrap_or_else(|| AUTO.to_string());
+            let leed_port =
+                resolve(&leed_spec, DeviceKind::Leed).expect("LEED controller not found");
+            monitor(&leed_port, vec![soft_send, leed_listen_in], leed_recv)
+                .unwrap()
+                .state
+        };
+
+        (soft_monitor.state, leed_state)
+    };
 
     enable_raw_mode()?;
     stdout().execute(EnterAlternateScreen)?;
@@ -50,19 +103,26 @@ fn main() -> io::Result<()> {
     let mut running = true;
 
     while running {
+        if let Ok(state) = soft_state.lock() {
+            counters.soft_link = *state;
+        }
+        if let Ok(state) = leed_state.lock() {
+            counters.leed_link = *state;
+        }
+
         while let Ok(buf) = soft_listen_out.try_recv() {
-            if let Some(msg) = buf_to_msg_string(&buf) {
+            if let Some(msg) = buf_to_msg_string(&buf, &mut counters.soft_errors) {
                 software_messages.push_front(format!("[{}] {}", counters.soft, msg));
             }
             counters.soft += 1;
         }
 
         while let Ok(buf) = leed_listen_out.try_recv() {
-            if let Some(msg) = Message::from_bytes(&buf) {
+            if let Ok(msg) = Message::from_bytes(&buf) {
                 controller.update_from_message(msg, &mut leed_messages);
             }
 
-            if let Some(msg) = buf_to_msg_string(&buf) {
+            if let Some(msg) = buf_to_msg_string(&buf, &mut counters.leed_errors) {
                 leed_messages.push_front(format!("[{}] {}", counters.leed, msg));
             }
             counters.leed += 1;
@@ -75,6 +135,7 @@ fn main() -> io::Result<()> {
             ui(
                 frame,
                 &controller,
+                &counters,
                 software_messages.clone().into(),
                 leed_messages.clone().into(),
             );
@@ -104,6 +165,7 @@ fn handle_events() -> io::Result<bool> {
 fn ui(
     frame: &mut Frame,
     controller: &LEEDController,
+    counters: &Counters,
     software_messages: Vec<String>,
     leed_messages: Vec<String>,
 ) {
@@ -130,8 +192,16 @@ fn ui(
     )
     .split(main_layout[2]);
 
-    render_messages(frame, horiz_layout[0], "From: Software", software_messages);
-    render_messages(frame, horiz_layout[1], "LEED Controller", leed_messages);
+    let soft_title = format!(
+        "From: Software ({}, errors: {})",
+        counters.soft_link, counters.soft_errors
+    );
+    let leed_title = format!(
+        "LEED Controller ({}, errors: {})",
+        counters.leed_link, counters.leed_errors
+    );
+    render_messages(frame, horiz_layout[0], &soft_title, software_messages);
+    render_messages(frame, horiz_layout[1], &leed_title, leed_messages);
 
     render_controller(frame, controller_layout, controller);
 }
@@ -148,9 +218,9 @@ fn render_controller(frame: &mut Frame, area: Rect, c: &LEEDController) {
     let mut controls_content = Vec::from(
         [
             ("Beam Energy", &c.settings.beam_energy),
-            ("Suppressor", &c.settings.suppressor),
-            ("Lens 2 Set", &c.settings.lens2),
-            ("Lens 1/3 Set", &c.settings.lens1_3),
+            ("Suppressor", c.settings.suppressor.output()),
+            ("Lens 2 Set", c.settings.lens2.output()),
+            ("Lens 1/3 Set", c.settings.lens1_3.output()),
             ("Wehnheit", &c.settings.wehnheit),
             ("Emission", &c.settings.emission),
             ("Filament", &c.settings.filament),
@@ -161,12 +231,24 @@ fn render_controller(frame: &mut Frame, area: Rect, c: &LEEDController) {
 
     controls_content.extend(
         [
-            ("Beam current", c.currents.beam),
-            ("Emission current", c.currents.emission),
-            ("Filament current", c.currents.filament),
+            ("Beam current", Monitor::I0_MON),
+            ("Emission current", Monitor::EMI_MON),
+            ("Filament current", Monitor::IFIL_MON),
+            ("Beam energy mon", Monitor::BEAM_MON),
+            ("Wehnheit mon", Monitor::WEH_MON),
+            ("Screen mon", Monitor::SCR_MON),
+            ("Lens 1/3 mon", Monitor::L13_MON),
+            ("Lens 2 mon", Monitor::L2_MON),
+            ("Suppressor mon", Monitor::RET_MON),
         ]
-        .map(|(title, value)| format!("{}: {}", title, value)),
+        .map(|(title, monitor)| format!("{}: {}", title, c.monitor_reading(monitor))),
     );
+    if let Some(percentage) = c.suppressor_reading_percentage() {
+        controls_content.push(format!(
+            "Suppressor mon: {:.1}% of beam energy mon",
+            percentage
+        ));
+    }
 
     let list = List::new(controls_content)
         .block(Block::default().title(title.red()).borders(Borders::ALL));
@@ -174,14 +256,17 @@ fn render_controller(frame: &mut Frame, area: Rect, c: &LEEDController) {
     frame.render_widget(list, area);
 }
 
-fn buf_to_msg_string(bytes: &[u8; 6]) -> Option<String> {
-    if let Some(msg) = Message::from_bytes(bytes) {
-        match msg.tag {
-            Tag::ADC1 | Tag::ADC2 | Tag::ADC3 => None,
+fn buf_to_msg_string(bytes: &[u8; 6], errors: &mut ErrorCounts) -> Option<String> {
+    match Message::from_bytes(bytes) {
+        Ok(msg) => match msg.tag {
+            Tag::Monitor(_) => None,
+            Tag::Unknown(id) => Some(format!("Unknown ID ${:02X}: ${:04X}", id, msg.value)),
             _ => Some(format!("{:?}", msg)),
+        },
+        Err(err) => {
+            errors.record(&err);
+            Some(format!("{}: {:02X?}", err, bytes))
         }
-    } else {
-        Some(format!("Unhandled message: {:02X?}", bytes))
     }
 }
 
@@ -0,0 +1,12 @@
+// Command line helpers shared by the binaries
+
+// Removes "name value" from the arguments, returning the value
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.remove(index);
+    if index < args.len() {
+        Some(args.remove(index))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,363 @@
+use super::protocol::{FrameDecoder, RawMessage};
+use super::sniffer::{monitor, ConnectionState, MonitorHandle};
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// Shares the serial link to the controller over TCP.
+//
+// Arbitration: the control port takes one client at a time. The first client
+// to connect holds control until it disconnects, further control connections
+// are closed right away while it is held. Only the controlling client writes
+// to the controller, and only frames which pass RawMessage::parse.
+//
+// Observers connect to the observe port. They get every frame in both
+// directions, interleaved as they passed the bridge. What they send is dropped.
+//
+// The bridge sends no keepalive of its own, so the controller watchdog still
+// resets the outputs when the controlling client goes away.
+
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
+// Unsent bytes kept for a client that reads slowly, it is dropped beyond this
+const MAX_BACKLOG: usize = 64 * 1024;
+
+pub struct Bridge {
+    link: MonitorHandle,
+    to_leed: mpsc::Sender<[u8; 6]>,
+    from_leed: mpsc::Receiver<[u8; 6]>,
+    control_listener: TcpListener,
+    observe_listener: TcpListener,
+    controller: Option<Client>,
+    observers: Vec<Client>,
+    link_state: ConnectionState,
+    rejected_frames: u64,
+}
+
+struct Client {
+    stream: TcpStream,
+    peer: SocketAddr,
+    decoder: FrameDecoder,
+    backlog: VecDeque<u8>, // Not yet accepted by the socket
+}
+
+impl Client {
+    fn new(stream: TcpStream, peer: SocketAddr) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            peer,
+            decoder: FrameDecoder::new(),
+            backlog: VecDeque::new(),
+        })
+    }
+
+    // Frames received since the last call, None once the client is gone
+    fn receive(&mut self) -> Option<Vec<[u8; 6]>> {
+        let mut frames = Vec::new();
+        loop {
+            let mut buf: [u8; 64] = [0; 64];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return None,
+                Ok(count) => frames.extend(self.decoder.decode(&buf[..count])),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Some(frames),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return None,
+            }
+        }
+    }
+
+    // Queues the frame behind anything still unsent, false once the client is
+    // gone or has stopped reading
+    fn send(&mut self, frame: &[u8; 6]) -> bool {
+        self.backlog.extend(frame);
+        self.flush() && self.backlog.len() <= MAX_BACKLOG
+    }
+
+    // Writes as much of the backlog as the nonblocking socket takes,
+    // so frames are never cut short. False once the client is gone.
+    fn flush(&mut self) -> bool {
+        while !self.backlog.is_empty() {
+            let (pending, _) = self.backlog.as_slices();
+            match self.stream.write(pending) {
+                Ok(0) => return false,
+                Ok(count) => {
+                    self.backlog.drain(..count);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Bridge {
+    // Serial is an address as taken by sniffer::monitor
+    pub fn new(serial: &str, control_address: &str, observe_address: &str) -> io::Result<Self> {
+        let (to_leed, to_leed_recv) = mpsc::channel();
+        let (from_leed_send, from_leed) = mpsc::channel();
+        let link = monitor(serial, vec![from_leed_send], to_leed_recv)?;
+
+        let control_listener = TcpListener::bind(control_address)?;
+        control_listener.set_nonblocking(true)?;
+        let observe_listener = TcpListener::bind(observe_address)?;
+        observe_listener.set_nonblocking(true)?;
+
+        info!(
+            "Bridging {}: control on {}, observers on {}",
+            serial,
+            control_listener.local_addr()?,
+            observe_listener.local_addr()?
+        );
+
+        Ok(Self {
+            link,
+            to_leed,
+            from_leed,
+            control_listener,
+            observe_listener,
+            controller: None,
+            observers: Vec::new(),
+            link_state: ConnectionState::Connected,
+            rejected_frames: 0,
+        })
+    }
+
+    pub fn control_address(&self) -> io::Result<SocketAddr> {
+        self.control_listener.local_addr()
+    }
+
+    pub fn observe_address(&self) -> io::Result<SocketAddr> {
+        self.observe_listener.local_addr()
+    }
+
+    // Runs until the serial link fails for good
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            if !self.poll()? {
+                thread::sleep(IDLE_SLEEP);
+            }
+
+            if self.link_state == ConnectionState::Failed {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Serial link failed",
+                ));
+            }
+        }
+    }
+
+    // One pass over all connections, true if anything was moved
+    pub fn poll(&mut self) -> io::Result<bool> {
+        self.update_link_state();
+        self.accept_controller()?;
+        self.accept_observers()?;
+
+        let mut busy = self.flush_clients();
+
+        let to_leed = match &mut self.controller {
+            Some(client) => match client.receive() {
+                Some(frames) => frames,
+                None => {
+                    info!("Controlling client {} left, control is free", client.peer);
+                    self.controller = None;
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
+        };
+
+        for frame in to_leed {
+            busy = true;
+            match RawMessage::parse(&frame) {
+                Ok(_) => {
+                    if self.to_leed.send(frame).is_err() {
+                        return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+                    }
+                    self.send_to_observers(&frame);
+                }
+                Err(err) => {
+                    self.rejected_frames += 1;
+                    warn!(
+                        "Rejected frame {:02X?} from controlling client: {}",
+                        frame, err
+                    );
+                }
+            }
+        }
+
+        while let Ok(frame) = self.from_leed.try_recv() {
+            busy = true;
+            if let Some(client) = &mut self.controller {
+                if !client.send(&frame) {
+                    info!("Controlling client {} left, control is free", client.peer);
+                    self.controller = None;
+                }
+            }
+            self.send_to_observers(&frame);
+        }
+
+        // Observers are read-only, drop whatever they send
+        self.observers.retain_mut(|observer| {
+            let alive = observer.receive().is_some();
+            if !alive {
+                info!("Observer {} left", observer.peer);
+            }
+            alive
+        });
+
+        Ok(busy)
+    }
+
+    pub fn rejected_frames(&self) -> u64 {
+        self.rejected_frames
+    }
+
+    fn update_link_state(&mut self) {
+        let state = match self.link.state.lock() {
+            Ok(state) => *state,
+            Err(_) => ConnectionState::Failed,
+        };
+
+        if state != self.link_state {
+            match state {
+                ConnectionState::Failed => error!("Serial link {}", state),
+                _ => info!("Serial link {}", state),
+            }
+            self.link_state = state;
+        }
+    }
+
+    fn accept_controller(&mut self) -> io::Result<()> {
+        loop {
+            let (stream, peer) = match self.control_listener.accept() {
+                Ok(connection) => connection,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            if let Some(current) = &self.controller {
+                warn!("Refused control to {}, held by {}", peer, current.peer);
+                continue; // Dropping the stream closes it
+            }
+
+            info!("Control taken by {}", peer);
+            self.controller = Some(Client::new(stream, peer)?);
+        }
+    }
+
+    fn accept_observers(&mut self) -> io::Result<()> {
+        loop {
+            match self.observe_listener.accept() {
+                Ok((stream, peer)) => {
+                    info!("Observer {} joined", peer);
+                    self.observers.push(Client::new(stream, peer)?);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Sends what is left over from earlier passes, true if anything was pending
+    fn flush_clients(&mut self) -> bool {
+        let pending = self
+            .controller
+            .iter()
+            .chain(&self.observers)
+            .any(|client| !client.backlog.is_empty());
+
+        if let Some(client) = &mut self.controller {
+            if !client.flush() {
+                info!("Controlling client {} left, control is free", client.peer);
+                self.controller = None;
+            }
+        }
+        self.observers.retain_mut(|observer| {
+            let alive = observer.flush();
+            if !alive {
+                info!("Dropped observer {}", observer.peer);
+            }
+            alive
+        });
+
+        pending
+    }
+
+    fn send_to_observers(&mut self, frame: &[u8; 6]) {
+        self.observers.retain_mut(|observer| {
+            let alive = observer.send(frame);
+            if !alive {
+                info!("Dropped observer {}", observer.peer);
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::protocol::{Control, Message, Tag};
+
+    fn frame(value: u32) -> [u8; 6] {
+        Message {
+            tag: Tag::Control(Control::SCR_SET),
+            value,
+        }
+        .to_bytes()
+        .expect("Message is encodable")
+    }
+
+    #[test]
+    fn slow_client_gets_whole_frames() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut reader = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, peer) = listener.accept()?;
+        let mut client = Client::new(stream, peer)?;
+
+        // Fill the socket until it pushes back, without reading
+        let mut sent = 0;
+        while client.backlog.is_empty() {
+            for _ in 0..1000 {
+                client.backlog.extend(frame(sent % 0x10000));
+                sent += 1;
+            }
+            assert!(client.flush(), "Client dropped");
+            assert!(sent < 10_000_000, "Socket never pushed back");
+        }
+        assert!(client.send(&frame(sent % 0x10000)), "Client dropped");
+        sent += 1;
+
+        let expected = sent as usize * 6;
+        let reading = thread::spawn(move || {
+            let mut bytes = vec![0; expected];
+            reader.read_exact(&mut bytes).map(|_| bytes)
+        });
+        while !client.backlog.is_empty() {
+            assert!(client.flush(), "Client dropped");
+            thread::sleep(IDLE_SLEEP);
+        }
+
+        let bytes = reading.join().expect("Reader thread ends")?;
+        let mut decoder = FrameDecoder::new();
+        // In chunks, like reads from the socket
+        let frames: Vec<_> = bytes
+            .chunks(64)
+            .flat_map(|chunk| decoder.decode(chunk))
+            .collect();
+        assert_eq!(decoder.discarded(), 0);
+        assert_eq!(frames.len(), sent as usize);
+        assert!(frames
+            .iter()
+            .zip(0..)
+            .all(|(received, value)| *received == frame(value % 0x10000)));
+        Ok(())
+    }
+}
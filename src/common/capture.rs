@@ -0,0 +1,359 @@
+use super::protocol::FRAME_LEN;
+use log::{error, info};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Capture file format, all integers little endian:
+//
+//   Header:  "LEEDCAP" followed by the format version, 8 bytes
+//            u64  wall clock time of the start, microseconds since the
+//                 Unix epoch. Missing in version 1 captures.
+//   Record:  u64  microseconds since the start of the capture (monotonic)
+//            u8   direction, see Direction
+//            6 x u8  the frame as it was on the wire
+//
+// Frames are stored raw as the frame decoder split them off the stream,
+// including ones with an unknown ID which fail to parse as a Message, so a
+// capture can be replayed through the normal decoding path. Bytes dropped
+// while resyncing, such as a frame with a bad checksum, are not recorded.
+
+const MAGIC: &[u8; 7] = b"LEEDCAP";
+const VERSION: u8 = 2;
+const VERSION_WITHOUT_START: u8 = 1; // Still read
+const RECORD_LEN: usize = 8 + 1 + FRAME_LEN;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1); // Bounds what is lost on a crash
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    HostToController = 0,
+    ControllerToHost = 1,
+}
+
+impl Direction {
+    pub fn reverse(self) -> Self {
+        match self {
+            Direction::HostToController => Direction::ControllerToHost,
+            Direction::ControllerToHost => Direction::HostToController,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Direction::HostToController),
+            1 => Ok(Direction::ControllerToHost),
+            _ => Err(invalid_data(format!("Bad direction {}", byte))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Record {
+    pub time: Duration, // Since the start of the capture
+    pub direction: Direction,
+    pub frame: [u8; 6],
+}
+
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+    start: Instant,
+    last_flush: Instant,
+    records: u64,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Self::new(Box::new(BufWriter::new(file)))
+    }
+
+    pub fn new(mut writer: Box<dyn Write + Send>) -> io::Result<Self> {
+        let start_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_micros() as u64;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&start_micros.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+            last_flush: Instant::now(),
+            records: 0,
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, frame: &[u8; 6]) -> io::Result<()> {
+        let micros = self.start.elapsed().as_micros() as u64;
+
+        let mut buf = [0; RECORD_LEN];
+        buf[..8].copy_from_slice(&micros.to_le_bytes());
+        buf[8] = direction as u8;
+        buf[9..].copy_from_slice(frame);
+
+        self.writer.write_all(&buf)?;
+        self.records += 1;
+
+        if self.last_flush.elapsed() > FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.writer.flush()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(err) = self.writer.flush() {
+            error!("Failed flushing capture: {}", err);
+        }
+    }
+}
+
+// Reads records from a capture, in the order they were recorded
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    start_time: Option<SystemTime>,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+
+        if &header[..7] != MAGIC {
+            return Err(invalid_data("Not a LEED capture".to_string()));
+        }
+        let start_time = match header[7] {
+            VERSION => {
+                let mut micros = [0; 8];
+                reader.read_exact(&mut micros)?;
+                Some(UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(micros)))
+            }
+            VERSION_WITHOUT_START => None,
+            version => {
+                return Err(invalid_data(format!(
+                    "Unsupported capture version {}",
+                    version
+                )))
+            }
+        };
+
+        Ok(Self { reader, start_time })
+    }
+
+    // Wall clock time the capture started, None for version 1 captures
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.start_time
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = [0; RECORD_LEN];
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(err) => return Some(Err(err)),
+        }
+
+        let mut micros = [0; 8];
+        micros.copy_from_slice(&buf[..8]);
+        let mut frame = [0; 6];
+        frame.copy_from_slice(&buf[9..]);
+
+        Some(Direction::from_byte(buf[8]).map(|direction| Record {
+            time: Duration::from_micros(u64::from_le_bytes(micros)),
+            direction,
+            frame,
+        }))
+    }
+}
+
+// Plays a capture back into channels, like monitor threads would deliver it.
+// Pacing follows the recorded timestamps, divided by speed.
+// With speed of zero or less, frames are sent without delay.
+pub fn replay<P: AsRef<Path>>(
+    path: P,
+    speed: f32,
+    to_controller: Vec<mpsc::Sender<[u8; 6]>>,
+    from_controller: Vec<mpsc::Sender<[u8; 6]>>,
+) -> io::Result<JoinHandle<()>> {
+    let capture = CaptureReader::open(path)?;
+
+    Ok(thread::spawn(move || {
+        let start = Instant::now();
+
+        for record in capture {
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    error!("Replay stopped: {}", err);
+                    return;
+                }
+            };
+
+            if speed > 0.0 {
+                let due = record.time.div_f32(speed);
+                if let Some(wait) = due.checked_sub(start.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+
+            let senders = match record.direction {
+                Direction::HostToController => &to_controller,
+                Direction::ControllerToHost => &from_controller,
+            };
+            for sender in senders {
+                if sender.send(record.frame).is_err() {
+                    return;
+                }
+            }
+        }
+
+        info!("Replay finished");
+    }))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Writer whose bytes stay readable after the recorder took it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn bytes(&self) -> Vec<u8> {
+            self.0.lock().map(|bytes| bytes.clone()).unwrap_or_default()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut bytes = self.0.lock().map_err(|_| io::Error::other("Poisoned"))?;
+            bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const HEADER_LEN: usize = 16;
+
+    const REQUEST: [u8; 6] = [0x02, 0x20, 0x00, 0x00, 0x22, 0x03];
+    const REPLY: [u8; 6] = [0x02, 0x20, 0x00, 0x0D, 0x2F, 0x03];
+
+    fn recorded() -> io::Result<Vec<u8>> {
+        let buffer = SharedBuffer::default();
+        let mut recorder = Recorder::new(Box::new(buffer.clone()))?;
+        recorder.record(Direction::HostToController, &REQUEST)?;
+        thread::sleep(Duration::from_millis(20));
+        recorder.record(Direction::ControllerToHost, &REPLY)?;
+        assert_eq!(recorder.records(), 2);
+        drop(recorder);
+        Ok(buffer.bytes())
+    }
+
+    #[test]
+    fn records_round_trip() -> io::Result<()> {
+        let bytes = recorded()?;
+        assert_eq!(bytes.len(), HEADER_LEN + 2 * RECORD_LEN);
+
+        let reader = CaptureReader::new(bytes.as_slice())?;
+        let started = reader.start_time().and_then(|start| start.elapsed().ok());
+        assert!(started.is_some_and(|started| started < Duration::from_secs(10)));
+        let records = reader.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::HostToController);
+        assert_eq!(records[0].frame, REQUEST);
+        assert_eq!(records[1].direction, Direction::ControllerToHost);
+        assert_eq!(records[1].frame, REPLY);
+        assert!(records[1].time >= records[0].time + Duration::from_millis(20));
+        assert!(records[1].time < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_record_ends_capture() -> io::Result<()> {
+        let bytes = recorded()?;
+        let truncated = &bytes[..bytes.len() - 3];
+
+        let records = CaptureReader::new(truncated)?.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].frame, REQUEST);
+        Ok(())
+    }
+
+    #[test]
+    fn bad_direction_is_an_error() -> io::Result<()> {
+        let mut bytes = recorded()?;
+        bytes[HEADER_LEN + 8] = 7;
+
+        let mut reader = CaptureReader::new(bytes.as_slice())?;
+        let err = reader.next().and_then(Result::err).map(|err| err.kind());
+        assert_eq!(err, Some(io::ErrorKind::InvalidData));
+        Ok(())
+    }
+
+    #[test]
+    fn reads_version_1_without_start_time() -> io::Result<()> {
+        let bytes = recorded()?;
+        let mut version_1 = b"LEEDCAP\x01".to_vec();
+        version_1.extend_from_slice(&bytes[HEADER_LEN..]);
+
+        let reader = CaptureReader::new(version_1.as_slice())?;
+        assert_eq!(reader.start_time(), None);
+        assert_eq!(reader.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_version() -> io::Result<()> {
+        let bytes = recorded()?;
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        let err = CaptureReader::new(bad_magic.as_slice()).err();
+        assert_eq!(err.map(|err| err.kind()), Some(io::ErrorKind::InvalidData));
+
+        let mut bad_version = bytes.clone();
+        bad_version[7] = VERSION + 1;
+        let err = CaptureReader::new(bad_version.as_slice()).err();
+        assert_eq!(err.map(|err| err.kind()), Some(io::ErrorKind::InvalidData));
+
+        let err = CaptureReader::new(&bytes[..4]).err();
+        assert_eq!(
+            err.map(|err| err.kind()),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+        Ok(())
+    }
+}
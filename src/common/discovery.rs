@@ -0,0 +1,230 @@
+use super::protocol::{FrameDecoder, Message, Tag};
+use super::transport::{SerialTransport, Transport};
+use crate::motors_client;
+use log::{info, warn};
+use serialport::{SerialPortInfo, SerialPortType};
+use std::io;
+use std::time::{Duration, Instant};
+
+// Finds devices among the serial ports, since USB enumeration order
+// changes between reboots.
+//
+// Port specs accepted by resolve:
+//   auto                 Probe all ports for the wanted device
+//   usb-serial=<serial>  Port with the given USB serial number
+//   usb=<vid>:<pid>      Port with the given USB vendor and product id (hex)
+//   anything else        Used as is, a port name or "tcp://host:port"
+
+pub const AUTO: &str = "auto";
+
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Leed,
+    Motors,
+}
+
+pub fn resolve(spec: &str, kind: DeviceKind) -> io::Result<String> {
+    if spec == AUTO {
+        discover(kind).ok_or_else(|| not_found(format!("No port answers as {:?} device", kind)))
+    } else {
+        resolve_pinned(spec)
+    }
+}
+
+// Like resolve, for ports which cannot be probed
+pub fn resolve_pinned(spec: &str) -> io::Result<String> {
+    if let Some(serial) = spec.strip_prefix("usb-serial=") {
+        return find_port(|usb_serial, _, _| usb_serial == Some(serial))
+            .ok_or_else(|| not_found(format!("No USB port with serial number {}", serial)));
+    }
+
+    if let Some(ids) = spec.strip_prefix("usb=") {
+        let (vid, pid) = parse_vid_pid(ids)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Expected usb=VID:PID"))?;
+        return find_port(|_, port_vid, port_pid| port_vid == vid && port_pid == pid)
+            .ok_or_else(|| not_found(format!("No USB port with id {:04x}:{:04x}", vid, pid)));
+    }
+
+    Ok(spec.to_string())
+}
+
+// First port identified as the wanted device
+pub fn discover(kind: DeviceKind) -> Option<String> {
+    available_ports()
+        .into_iter()
+        .map(|port| port.port_name)
+        .find(|port_name| identify(port_name) == Some(kind))
+}
+
+pub fn identify(port_name: &str) -> Option<DeviceKind> {
+    let mut port = match SerialTransport::open(port_name, PROBE_TIMEOUT) {
+        Ok(port) => port,
+        Err(err) => {
+            info!("Skipping {}: {}", port_name, err);
+            return None;
+        }
+    };
+
+    let kind = identify_transport(&mut port);
+    info!("Probed {}: {:?}", port_name, kind);
+    kind
+}
+
+// The motors are probed first. The motors server reads lines of JSON, a LEED
+// frame sent ahead of its request would end up in the same line.
+// A LEED controller resyncs on the next frame after the JSON line.
+pub fn identify_transport(port: &mut dyn Transport) -> Option<DeviceKind> {
+    if motors_client::probe(port) {
+        Some(DeviceKind::Motors)
+    } else if probe_leed(port) {
+        Some(DeviceKind::Leed)
+    } else {
+        None
+    }
+}
+
+// Sends a status request, a LEED controller replies immediately
+pub fn probe_leed(port: &mut dyn Transport) -> bool {
+    let request = Message {
+        tag: Tag::Status,
+        value: 0,
+    };
+    let Ok(bytes) = request.to_bytes() else {
+        return false;
+    };
+
+    if port.write_all(&bytes).is_err() {
+        return false;
+    }
+
+    let is_status = |frame: &[u8; 6]| matches!(Message::from_bytes(frame), Ok(reply) if reply.tag == Tag::Status);
+
+    let mut decoder = FrameDecoder::new();
+    let start = Instant::now();
+    while start.elapsed() < PROBE_TIMEOUT {
+        let mut buf: [u8; 64] = [0; 64];
+        if let Ok(count) = port.read(&mut buf) {
+            if decoder.decode(&buf[..count]).iter().any(is_status) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+fn available_ports() -> Vec<SerialPortInfo> {
+    serialport::available_ports().unwrap_or_else(|err| {
+        warn!("Cannot enumerate serial ports: {}", err);
+        Vec::new()
+    })
+}
+
+fn find_port<F>(matches: F) -> Option<String>
+where
+    F: Fn(Option<&str>, u16, u16) -> bool,
+{
+    available_ports()
+        .into_iter()
+        .find(|port| match &port.port_type {
+            SerialPortType::UsbPort(usb) => matches(usb.serial_number.as_deref(), usb.vid, usb.pid),
+            _ => false,
+        })
+        .map(|port| port.port_name)
+}
+
+fn parse_vid_pid(ids: &str) -> Option<(u16, u16)> {
+    let (vid, pid) = ids.split_once(':')?;
+    Some((
+        u16::from_str_radix(vid, 16).ok()?,
+        u16::from_str_radix(pid, 16).ok()?,
+    ))
+}
+
+fn not_found(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::simulator::{serve, Simulator};
+    use crate::common::transport::ChannelTransport;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    // Answers like py/motors_server.py, counting the lines it cannot parse
+    // (on which the server used to crash)
+    #[derive(Default)]
+    struct MotorsLine {
+        line: Vec<u8>,
+        replies: VecDeque<u8>,
+        bad_lines: usize,
+    }
+
+    impl Read for MotorsLine {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.replies.is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let count = buf.len().min(self.replies.len());
+            for (byte, reply) in buf.iter_mut().zip(self.replies.drain(..count)) {
+                *byte = reply;
+            }
+            Ok(count)
+        }
+    }
+
+    impl Write for MotorsLine {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &byte in buf {
+                if byte != b'\n' {
+                    self.line.push(byte);
+                    continue;
+                }
+                let line = std::mem::take(&mut self.line);
+                match serde_json::from_slice::<serde_json::Value>(&line) {
+                    Ok(command) if command["tag"] == "get_pos" => self
+                        .replies
+                        .extend(b"{\"tag\": \"CurrentPos\", \"x\": 1, \"y\": 2}\n"),
+                    Ok(_) => {}
+                    Err(_) => self.bad_lines += 1,
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MotorsLine {
+        fn describe(&self) -> String {
+            "motors".to_string()
+        }
+    }
+
+    #[test]
+    fn motors_server_only_sees_json_lines() {
+        let mut port = MotorsLine::default();
+        assert_eq!(identify_transport(&mut port), Some(DeviceKind::Motors));
+        assert_eq!(port.bad_lines, 0);
+    }
+
+    #[test]
+    fn leed_probe_works_after_motors_probe() {
+        let (mut host_end, sim_end) = ChannelTransport::pair(PROBE_TIMEOUT);
+        serve(Arc::new(Mutex::new(Simulator::new())), Box::new(sim_end));
+        assert_eq!(identify_transport(&mut host_end), Some(DeviceKind::Leed));
+    }
+
+    #[test]
+    fn nothing_answering_is_unidentified() {
+        let (mut host_end, _other_end) = ChannelTransport::pair(Duration::from_millis(10));
+        assert_eq!(identify_transport(&mut host_end), None);
+    }
+}
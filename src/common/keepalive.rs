@@ -0,0 +1,186 @@
+use super::protocol::{Message, Tag};
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// If no valid frame arrives for this long, the controller sets
+// all DACs to $0000 and clears the digital outputs.
+pub const WATCHDOG: Duration = Duration::from_secs(1);
+
+// Status requests not answered within this time are taken as lost
+const REPLY_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveConfig {
+    pub interval: Duration, // Send a keepalive frame when the link has been quiet this long
+    pub alarm_gap: Duration, // Raise an alarm for gaps longer than this
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: WATCHDOG / 4,
+            alarm_gap: WATCHDOG * 3 / 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeepaliveStats {
+    pub keepalives_sent: u32,
+    pub max_gap: Duration,
+    pub alarms: u32,
+    pub last_alarm: Option<Instant>,
+}
+
+// Runs next to the port, so frames keep flowing when the UI stalls.
+// Status requests are used as keepalive, since their content is arbitrary.
+// Their replies are held back, as the host could take one for the reply
+// to its own Status request.
+pub struct Keepalive {
+    config: KeepaliveConfig,
+    frame: [u8; 6],
+    last_frame: Option<Instant>,
+    status_requests: VecDeque<(Instant, bool)>, // Awaiting a reply, true for keepalives
+    stats: Arc<Mutex<KeepaliveStats>>,
+}
+
+impl Keepalive {
+    pub fn new(config: KeepaliveConfig) -> Self {
+        let request = Message {
+            tag: Tag::Status,
+            value: 0,
+        };
+
+        Self {
+            config,
+            frame: request
+                .to_bytes()
+                .expect("Status request is always encodable"),
+            last_frame: None,
+            status_requests: VecDeque::new(),
+            stats: Arc::new(Mutex::new(KeepaliveStats::default())),
+        }
+    }
+
+    // Shared handle for reading the stats from another thread
+    pub fn stats(&self) -> Arc<Mutex<KeepaliveStats>> {
+        self.stats.clone()
+    }
+
+    // Frame to send now, if the link has been quiet for the keepalive interval
+    pub fn due(&self) -> Option<[u8; 6]> {
+        match self.last_frame {
+            Some(last) if last.elapsed() < self.config.interval => None,
+            _ => Some(self.frame),
+        }
+    }
+
+    // Call for every frame written to the controller
+    pub fn frame_sent(&mut self, frame: &[u8; 6], keepalive: bool) {
+        let now = Instant::now();
+        let gap = self.last_frame.map(|last| now.duration_since(last));
+        self.last_frame = Some(now);
+
+        if is_status(frame) {
+            self.expire_requests(now);
+            self.status_requests.push_back((now, keepalive));
+        }
+
+        if let Ok(mut stats) = self.stats.lock() {
+            if keepalive {
+                stats.keepalives_sent += 1;
+            }
+
+            if let Some(gap) = gap {
+                stats.max_gap = stats.max_gap.max(gap);
+                if gap >= self.config.alarm_gap {
+                    stats.alarms += 1;
+                    stats.last_alarm = Some(now);
+                    warn!(
+                        "No frame sent for {:?}, watchdog resets after {:?}",
+                        gap, WATCHDOG
+                    );
+                }
+            }
+        }
+    }
+
+    // Call for every frame received. False for the reply to a keepalive,
+    // which should not be passed on to the host.
+    // The controller answers requests in order, so a Status reply belongs
+    // to the oldest Status request still waiting.
+    pub fn forward_reply(&mut self, frame: &[u8; 6]) -> bool {
+        if !is_status(frame) {
+            return true;
+        }
+
+        self.expire_requests(Instant::now());
+        match self.status_requests.pop_front() {
+            Some((_, true)) => {
+                debug!("Dropped reply to keepalive");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn expire_requests(&mut self, now: Instant) {
+        while let Some((sent, _)) = self.status_requests.front() {
+            if now.duration_since(*sent) < REPLY_TIMEOUT {
+                break;
+            }
+            self.status_requests.pop_front();
+        }
+    }
+}
+
+fn is_status(frame: &[u8; 6]) -> bool {
+    Message::from_bytes(frame).is_ok_and(|message| message.tag == Tag::Status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(tag: Tag) -> [u8; 6] {
+        Message { tag, value: 0 }
+            .to_bytes()
+            .expect("Message is encodable")
+    }
+
+    #[test]
+    fn keepalive_reply_is_dropped() {
+        let mut keepalive = Keepalive::new(KeepaliveConfig::default());
+        let status = frame(Tag::Status);
+
+        keepalive.frame_sent(&status, false);
+        keepalive.frame_sent(&status, true);
+        assert!(keepalive.forward_reply(&status));
+        assert!(!keepalive.forward_reply(&status));
+
+        keepalive.frame_sent(&status, true);
+        keepalive.frame_sent(&status, false);
+        assert!(!keepalive.forward_reply(&status));
+        assert!(keepalive.forward_reply(&status));
+    }
+
+    #[test]
+    fn other_replies_are_forwarded() {
+        let mut keepalive = Keepalive::new(KeepaliveConfig::default());
+        keepalive.frame_sent(&frame(Tag::Status), true);
+        assert!(keepalive.forward_reply(&frame(Tag::DigOut)));
+        assert!(!keepalive.forward_reply(&frame(Tag::Status)));
+    }
+
+    #[test]
+    fn unanswered_keepalive_expires() {
+        let mut keepalive = Keepalive::new(KeepaliveConfig::default());
+        let status = frame(Tag::Status);
+        keepalive.frame_sent(&status, true);
+        std::thread::sleep(REPLY_TIMEOUT);
+        keepalive.frame_sent(&status, false);
+        assert!(keepalive.forward_reply(&status));
+    }
+}
@@ -1,40 +1,19 @@
-use super::protocol::{Control, Message, Tag};
+use super::preset::Preset;
+use super::protocol::{
+    Control, DigOutBits, ErrorCounts, Message, Monitor, ProtocolError, StatusBits, Tag,
+};
+use super::setting::{DirectSetting, RampedSetting, Setting};
+use super::settings_file::{self, Quantity, SettingsFile, SettingsFileError};
+use super::sniffer::ConnectionState;
+use super::transaction::{Event, Transactions};
 
-use log::{error, info};
+use log::{error, info, warn};
 use std::collections::VecDeque;
 use std::fmt::Display;
-use std::sync::mpsc::{self, Receiver};
+use std::path::Path;
 use std::time::{Duration, Instant};
 
-struct Ramp {
-    last_time: Instant,
-}
-
-impl Ramp {
-    pub fn new() -> Self {
-        Self {
-            last_time: Instant::now(),
-        }
-    }
-
-    pub fn ready(&mut self) -> bool {
-        let now = Instant::now();
-        let time_diff = now.duration_since(self.last_time);
-
-        if time_diff > Duration::from_millis(1000) {
-            self.last_time = now;
-            true
-        } else {
-            false
-        }
-    }
-}
-
-enum ValueSetter {
-    Direct,
-    Ramped(Ramp),
-}
-
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Unit {
     Ampere,
     MicroAmpere,
@@ -61,17 +40,307 @@ impl Display for Unit {
     }
 }
 
+impl Unit {
+    // Symbol of the unit without prefix, and the scale of this unit to it
+    fn base(&self) -> (&'static str, f64) {
+        match self {
+            Unit::Ampere => ("A", 1.0),
+            Unit::MicroAmpere => ("A", 1e-6),
+            Unit::Volt => ("V", 1.0),
+            Unit::KiloVolt => ("V", 1e3),
+            Unit::ElectronVolt => ("eV", 1.0),
+            Unit::Percentage => ("%", 1.0),
+        }
+    }
+
+    // Factor converting values given in the symbol to this unit.
+    // The symbol may carry a metric prefix, "7000 V" is 7 kV. An empty symbol
+    // means this unit. None when the symbol is not a form of this unit.
+    pub fn factor_from(&self, symbol: &str) -> Option<f64> {
+        let (base, scale) = self.base();
+        if symbol.is_empty() {
+            return Some(1.0);
+        }
+        if symbol == base {
+            return Some(1.0 / scale);
+        }
+        if *self == Unit::Percentage {
+            return None;
+        }
+
+        let prefix = match symbol.strip_suffix(base)? {
+            "M" => 1e6,
+            "k" => 1e3,
+            "m" => 1e-3,
+            "u" | "µ" => 1e-6,
+            "n" => 1e-9,
+            _ => return None,
+        };
+        Some(prefix / scale)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetError {
+    UnknownControl(String),
+    WrongUnit {
+        control: String,
+        unit: String,
+    },
+    // Value and bounds in the unit of the control
+    OutOfRange {
+        control: String,
+        value: f32,
+        min: f32,
+        max: f32,
+        unit: String,
+    },
+    ShuttingDown,
+}
+
+impl Display for TargetError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetError::UnknownControl(name) => write!(formatter, "Unknown control: {}", name),
+            TargetError::WrongUnit { control, unit } => {
+                write!(
+                    formatter,
+                    "{}: {} is not a unit of this control",
+                    control, unit
+                )
+            }
+            TargetError::OutOfRange {
+                control,
+                value,
+                min,
+                max,
+                unit,
+            } => write!(
+                formatter,
+                "{}: {} {} is outside {} - {} {}",
+                control, value, unit, min, max, unit
+            ),
+            TargetError::ShuttingDown => write!(formatter, "Shutting down, targets are fixed"),
+        }
+    }
+}
+
+impl std::error::Error for TargetError {}
+
+#[derive(Debug)]
+pub enum PresetError {
+    File(SettingsFileError),
+    StartupIncomplete,
+    ShuttingDown,
+}
+
+impl Display for PresetError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresetError::File(err) => write!(formatter, "{}", err),
+            PresetError::StartupIncomplete => write!(formatter, "Startup not complete"),
+            PresetError::ShuttingDown => write!(formatter, "Shutting down, targets are fixed"),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<SettingsFileError> for PresetError {
+    fn from(err: SettingsFileError) -> Self {
+        PresetError::File(err)
+    }
+}
+
+// Value given in the symbol converted to the unit, and checked against the bounds.
+// A unit of None is dimensionless, then the symbol has to be empty.
+fn checked_value(
+    control: &str,
+    value: f32,
+    symbol: &str,
+    unit: Option<Unit>,
+    min: f32,
+    max: f32,
+) -> Result<f32, TargetError> {
+    let factor = match unit {
+        Some(unit) => unit.factor_from(symbol),
+        None => symbol.is_empty().then_some(1.0),
+    };
+    let Some(factor) = factor else {
+        return Err(TargetError::WrongUnit {
+            control: control.to_string(),
+            unit: symbol.to_string(),
+        });
+    };
+
+    let value = (value as f64 * factor) as f32;
+    if !(min..=max).contains(&value) {
+        return Err(TargetError::OutOfRange {
+            control: control.to_string(),
+            value,
+            min,
+            max,
+            unit: unit.map(|unit| unit.to_string()).unwrap_or_default(),
+        });
+    }
+    Ok(value)
+}
+
 pub enum Range {
     Max(f32, Unit),
     MinMax(f32, f32, Unit),
 }
 
+impl Range {
+    // Maps a raw value in 0..=domain_max onto the physical range
+    pub fn to_physical(&self, raw: i32, domain_max: i32) -> f32 {
+        let ratio = raw as f32 / domain_max as f32;
+        match self {
+            Range::Max(max_value, _) => ratio * max_value,
+            Range::MinMax(min_value, max_value, _) => min_value + ratio * (max_value - min_value),
+        }
+    }
+
+    // Inverse of to_physical, clamped to 0..=domain_max
+    pub fn to_raw(&self, value: f32, domain_max: i32) -> i32 {
+        let ratio = match self {
+            Range::Max(max_value, _) => value / max_value,
+            Range::MinMax(min_value, max_value, _) => (value - min_value) / (max_value - min_value),
+        };
+        ((ratio * domain_max as f32).round() as i32).clamp(0, domain_max)
+    }
+
+    // Lowest and highest physical value
+    pub fn bounds(&self) -> (f32, f32) {
+        match self {
+            Range::Max(max_value, _) => (0.0, *max_value),
+            Range::MinMax(min_value, max_value, _) => (*min_value, *max_value),
+        }
+    }
+
+    // Physical distance from the start to the end of the range
+    pub fn span(&self) -> f32 {
+        match self {
+            Range::Max(max_value, _) => *max_value,
+            Range::MinMax(min_value, max_value, _) => max_value - min_value,
+        }
+    }
+
+    pub fn unit(&self) -> Unit {
+        match self {
+            Range::Max(_, unit) | Range::MinMax(_, _, unit) => *unit,
+        }
+    }
+
+    // The same physical units per count, continued up to another maximum count
+    pub fn rescaled(&self, domain_max: i32, new_domain_max: i32) -> Range {
+        let factor = new_domain_max as f32 / domain_max as f32;
+        match *self {
+            Range::Max(max_value, unit) => Range::Max(max_value * factor, unit),
+            Range::MinMax(min_value, max_value, unit) => Range::MinMax(
+                min_value,
+                min_value + (max_value - min_value) * factor,
+                unit,
+            ),
+        }
+    }
+}
+
+// Maximum DAC count of each setpoint and its physical range there (10 V output)
+pub fn output_scale(control: Control) -> (i32, Range) {
+    match control {
+        Control::IFIL_SET1 => (63999, Range::Max(2.7, Unit::Ampere)),
+        Control::BEAM_SET_INT => (63999, Range::Max(1000.0, Unit::ElectronVolt)),
+        Control::WEH_SET => (63999, Range::Max(100.0, Unit::Volt)),
+        Control::EMI_SET | Control::EMI_MAX => (16959, Range::Max(50.0, Unit::MicroAmpere)),
+        Control::SCR_SET => (63999, Range::Max(7.0, Unit::KiloVolt)),
+        Control::L2_SET => (23734, Range::MinMax(-20.0, 1100.0, Unit::Volt)),
+        Control::L13_SET => (55522, Range::MinMax(-20.0, 2600.0, Unit::Volt)),
+        Control::RET_SET_INT => (35199, Range::Max(1100.0, Unit::Volt)),
+    }
+}
+
+// ADC input conversion: $0000 = 0 V, $FFFF = 10.24 V
+pub const ADC_MAX: i32 = 0xFFFF;
+pub const ADC_FULL_SCALE_VOLTS: f32 = 10.24;
+
+// Physical range of each monitor over the full ADC input range.
+// ADC and DAC share the conversion, so a monitor has the volts per count
+// of the setpoint it reads back. The suppressor monitor reads its voltage,
+// see LEEDController::suppressor_reading_percentage for the percentage.
+// The beam current monitor reads the ADC input voltage, see monitor_calibrated.
+pub fn monitor_range(monitor: Monitor) -> Range {
+    let control = match monitor {
+        Monitor::L13_MON => Control::L13_SET,
+        Monitor::EMI_MON => Control::EMI_SET,
+        Monitor::L2_MON => Control::L2_SET,
+        Monitor::BEAM_MON => Control::BEAM_SET_INT,
+        Monitor::RET_MON => Control::RET_SET_INT,
+        Monitor::SCR_MON => Control::SCR_SET,
+        Monitor::IFIL_MON => Control::IFIL_SET1,
+        Monitor::WEH_MON => Control::WEH_SET,
+        Monitor::I0_MON => return Range::Max(ADC_FULL_SCALE_VOLTS, Unit::Volt),
+    };
+    let (domain_max, range) = output_scale(control);
+    range.rescaled(domain_max, ADC_MAX)
+}
+
+// Neither the manual nor the notes give the scaling of the beam current
+// monitor, so it is shown in volts until it is measured
+pub fn monitor_calibrated(monitor: Monitor) -> bool {
+    monitor != Monitor::I0_MON
+}
+
+// A monitor value converted to physical units
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub value: f32,
+    pub unit: Unit,
+    pub raw: i32,
+    pub calibrated: bool, // False when the value is the ADC input voltage
+}
+
+impl Reading {
+    pub fn from_raw(monitor: Monitor, raw: i32) -> Self {
+        let range = monitor_range(monitor);
+        Self {
+            value: range.to_physical(raw, ADC_MAX),
+            unit: range.unit(),
+            raw,
+            calibrated: monitor_calibrated(monitor),
+        }
+    }
+}
+
+impl Display for Reading {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "{:.3} {}", self.value, self.unit)?;
+        if !self.calibrated {
+            write!(formatter, " uncalibrated")?;
+        }
+        write!(formatter, "  ({} / {})", self.raw, ADC_MAX)
+    }
+}
+
+// Ramp rate in the units of the control, per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ramp {
+    pub rate: f32,
+    pub min_interval: Duration, // Shortest time between two steps
+}
+
+impl Ramp {
+    pub fn new(rate: f32, min_interval: Duration) -> Self {
+        Self { rate, min_interval }
+    }
+}
+
 pub struct ControlValue {
     pub name: String,
     pub current_value: i32,
-    setter: ValueSetter,
+    setting: Box<dyn Setting>,
+    ramp: Option<Ramp>, // Set when the setting is a RampedSetting made by set_ramp
     target_value: i32,
-    default: i32,
     domain_max: i32,
     range: Range,
     control: Control,
@@ -86,60 +355,48 @@ pub enum Adjustment {
 fn send_message(
     message_tag: Tag,
     value: i32,
-    sender: &mpsc::Sender<[u8; 6]>,
-) -> Result<(), mpsc::SendError<[u8; 6]>> {
+    link: &mut Transactions,
+) -> Result<(), ProtocolError> {
     let msg = Message {
         tag: message_tag,
         value: value as u32,
     };
 
-    if let Some(bytes) = msg.to_bytes() {
-        sender.send(bytes)
-    } else {
-        // TODO: Report error in suitable way
-        error!("Message not sent. Serialization failed: {:?}", msg);
-        Ok(())
-    }
+    link.request(msg)
 }
 
 impl Display for ControlValue {
     fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let current_ratio = self.current_value as f32 / self.domain_max as f32;
-        let target_ratio = self.target_value as f32 / self.domain_max as f32;
-        // let value = ratio *
-        let (cur, targ, unit) = match &self.range {
-            Range::Max(max_value, unit) => {
-                (current_ratio * max_value, target_ratio * max_value, unit)
-            }
-            Range::MinMax(min_value, max_value, unit) => (
-                min_value + current_ratio * (max_value - min_value),
-                min_value + target_ratio * (max_value - min_value),
-                unit,
-            ),
-        };
+        let cur = self.range.to_physical(self.current_value, self.domain_max);
+        let targ = self.range.to_physical(self.target_value, self.domain_max);
+        let unit = self.range.unit();
 
         write!(
             formatter,
             "{} [{}] {}  ({} / {})",
             cur, targ, unit, self.current_value, self.domain_max
-        )
+        )?;
+
+        if let Some(ramp) = &self.ramp {
+            write!(formatter, "  ramp {} {}/s", ramp.rate, unit)?;
+        }
+        Ok(())
     }
 }
 
 impl ControlValue {
     fn new(
         name: &str,
-        setter: ValueSetter,
-        default: i32,
+        setting: Box<dyn Setting>,
         control: Control,
         domain_max: i32,
         range: Range,
     ) -> Self {
         Self {
             current_value: 0,
-            setter,
-            target_value: default,
-            default,
+            target_value: setting.initial_value().unwrap_or(0),
+            setting,
+            ramp: None,
             control,
             domain_max,
             name: name.to_string(),
@@ -147,37 +404,33 @@ impl ControlValue {
         }
     }
 
-    fn update(&mut self, sender: &mpsc::Sender<[u8; 6]>) -> Result<(), mpsc::SendError<[u8; 6]>> {
-        match &mut self.setter {
-            ValueSetter::Direct => {
-                if self.target_value != self.current_value {
-                    send_message(Tag::Control(self.control), self.target_value, sender)
-                } else {
-                    Ok(())
-                }
-            }
-            ValueSetter::Ramped(ramp) => {
-                if ramp.ready() {
-                    let step = (self.domain_max as f32 / 500.0) as i32;
-                    if (self.target_value - self.current_value).abs() < step {
-                        Ok(())
-                    } else {
-                        let dir = if self.target_value < self.current_value {
-                            Adjustment::Down
-                        } else {
-                            Adjustment::Up
-                        };
-                        let value = self.next(self.current_value, dir);
-                        info!("Ramp {}: {}", self.name, value);
-                        send_message(Tag::Control(self.control), value, sender)
-                    }
-                } else {
-                    Ok(())
+    // Scaled like the setpoint output, see output_scale
+    fn for_output(name: &str, setting: Box<dyn Setting>, control: Control) -> Self {
+        let (domain_max, range) = output_scale(control);
+        Self::new(name, setting, control, domain_max, range)
+    }
+
+    fn update(&mut self, link: &mut Transactions) -> Result<(), ProtocolError> {
+        match self
+            .setting
+            .next_value(self.current_value, self.target_value, self.domain_max)
+        {
+            Some(value) => {
+                if value != self.target_value {
+                    info!("Ramp {}: {}", self.name, value);
                 }
+                send_message(Tag::Control(self.control), value, link)
             }
+            None => Ok(()),
         }
     }
 
+    // Value echoed by the controller
+    fn read_back(&mut self, value: i32) {
+        self.current_value = value;
+        self.setting.read_back(value);
+    }
+
     fn next(&self, start_value: i32, dir: Adjustment) -> i32 {
         let step = (self.domain_max as f32 / 500.0) as i32;
 
@@ -202,276 +455,2454 @@ impl ControlValue {
         self.target_value = self.next(self.target_value, adjustment)
     }
 
-    pub fn send_default(
-        &self,
-        sender: &mpsc::Sender<[u8; 6]>,
-    ) -> Result<(), mpsc::SendError<[u8; 6]>> {
-        send_message(Tag::Control(self.control), self.default, sender)
+    fn set_raw_target(&mut self, raw: i32) {
+        self.target_value = raw.clamp(0, self.domain_max);
     }
-}
 
-pub struct Currents {
-    pub beam: i32,
-    pub emission: i32,
-    pub filament: i32,
-}
+    // Sets the target in physical units, e.g. (7000.0, "V") for a kV control.
+    // Rejected outside the range, then the target stays as it was.
+    pub fn set_target(&mut self, value: f32, unit: &str) -> Result<(), TargetError> {
+        let (min, max) = self.range.bounds();
+        let value = checked_value(&self.name, value, unit, Some(self.range.unit()), min, max)?;
+        self.target_value = self.range.to_raw(value, self.domain_max);
+        Ok(())
+    }
 
-impl Currents {
-    fn new() -> Self {
-        Self {
-            beam: 0,
-            emission: 0,
-            filament: 0,
-        }
+    // Echoed value has reached the target
+    pub fn at_target(&self) -> bool {
+        self.current_value == self.target_value
     }
-}
 
-pub struct Settings {
-    pub beam_energy: ControlValue,
-    pub wehnheit: ControlValue,
-    pub emission: ControlValue,
-    pub filament: ControlValue,
-    pub screen: ControlValue,
-    pub lens1_3: ControlValue,
-    pub lens2: ControlValue,
-    pub suppressor: ControlValue,
-}
+    // Target in the units of the control
+    pub fn target(&self) -> f32 {
+        self.range.to_physical(self.target_value, self.domain_max)
+    }
 
-impl Settings {
-    fn update(&mut self, sender: &mpsc::Sender<[u8; 6]>) {
-        let controls = vec![
-            &mut self.beam_energy,
-            &mut self.wehnheit,
-            &mut self.emission,
-            &mut self.filament,
-            &mut self.screen,
-            &mut self.lens1_3,
-            &mut self.lens2,
-            &mut self.suppressor,
-        ];
+    // Last echoed value in the units of the control
+    pub fn current(&self) -> f32 {
+        self.range.to_physical(self.current_value, self.domain_max)
+    }
 
-        for control in controls {
-            if control.update(sender).is_err() {
-                error!("Failed updating control: {}", control.name);
-            }
-        }
+    // Time the ramp from the echoed value to the target takes, zero unless ramped
+    fn ramp_duration(&self) -> Duration {
+        let Some(ramp) = self.ramp else {
+            return Duration::ZERO;
+        };
+        let counts = (self.target_value - self.current_value).abs() as f32;
+        let distance = counts * self.range.span() / self.domain_max as f32;
+        Duration::try_from_secs_f32(distance / ramp.rate).unwrap_or(Duration::MAX)
     }
-}
 
-impl Settings {
-    fn new() -> Self {
-        Self {
-            filament: ControlValue::new(
-                "Filament",
-                ValueSetter::Ramped(Ramp::new()),
-                0,
-                Control::IFIL_SET1,
-                63999,
-                Range::Max(2.7, Unit::Ampere),
-            ),
-            beam_energy: ControlValue::new(
-                "Beam energy",
-                ValueSetter::Direct,
-                3500,
-                Control::BEAM_SET_INT,
-                63999,
-                Range::Max(1000.0, Unit::ElectronVolt),
-            ),
-            wehnheit: ControlValue::new(
-                "Wehnheit",
-                ValueSetter::Direct,
-                0,
-                Control::WEH_SET,
-                63999,
-                Range::Max(100.0, Unit::Volt),
-            ),
-            emission: ControlValue::new(
-                "Emission",
-                ValueSetter::Direct,
-                16959,
-                Control::EMI_SET,
-                16959,
-                Range::Max(50.0, Unit::MicroAmpere),
-            ),
-            screen: ControlValue::new(
-                "Screen",
-                ValueSetter::Direct,
-                63999,
-                Control::SCR_SET,
-                63999,
-                Range::Max(7.0, Unit::KiloVolt),
-            ),
-            // Lenses:
-            // Offset: -20 - 100V
-            // L2 Gain: 0 - 1.0
-            // L13 Gain: 0 - 2.5
-            // Output value: gain * 1000 + offset
-            lens2: ControlValue::new(
-                "Lens 2",
-                ValueSetter::Direct,
-                20000,
-                Control::L2_SET,
-                23734,
-                Range::MinMax(-20.0, 1100.0, Unit::Volt),
-            ),
-            lens1_3: ControlValue::new(
-                "Lens 1/3",
-                ValueSetter::Direct,
-                50000,
-                Control::L13_SET,
-                55522,
-                Range::MinMax(-20.0, 2500.0, Unit::Volt),
-            ),
-            suppressor: ControlValue::new(
-                "Suppressor",
-                ValueSetter::Direct,
-                26000,
-                Control::RET_SET_INT,
-                35199,
-                Range::MinMax(10.0, 110.0, Unit::Percentage),
-            ),
+    // Ramped controls start from their initial value and ramp up once running,
+    // the others go straight to their target
+    fn startup_value(&self) -> i32 {
+        match self.ramp {
+            Some(_) => self.setting.initial_value().unwrap_or(0),
+            None => self.target_value,
         }
     }
-}
 
-pub struct LEEDController {
-    pub currents: Currents, // Received from controller hardware
-    pub settings: Settings,
-    last_current_update: Instant,
-    adc_counter: u8,
-    defaults_counter: u8,
-}
+    // Raw target for a physical value, rejected outside the range
+    fn raw_in_range(&self, key: &str, value: f32) -> Result<i32, SettingsFileError> {
+        let (min, max) = self.range.bounds();
+        let value = check_range(key, value, min, max)?;
+        Ok(self.range.to_raw(value, self.domain_max))
+    }
 
-impl Drop for LEEDController {
-    fn drop(&mut self) {
-        self.graceful_exit();
+    // Raw target for a settings file entry, None when it is missing
+    fn raw_from_file(
+        &self,
+        file: &SettingsFile,
+        key: &str,
+    ) -> Result<Option<i32>, SettingsFileError> {
+        file_value(file, key, self.range.unit())?
+            .map(|value| self.raw_in_range(key, value))
+            .transpose()
     }
-}
 
-impl LEEDController {
-    pub fn new() -> Self {
-        Self {
-            currents: Currents::new(),
-            settings: Settings::new(),
-            last_current_update: Instant::now(),
-            adc_counter: 0,
-            defaults_counter: 0,
-        }
+    // Target as written to a settings file, with the fewest decimals
+    // which still give the same raw value when read back
+    fn target_quantity(&self) -> Quantity {
+        let target = self.target();
+        let value = (0..MAX_DECIMALS)
+            .map(|decimals| round_to_decimals(target, decimals))
+            .find(|value| self.range.to_raw(*value, self.domain_max) == self.target_value)
+            .unwrap_or(target);
+        Quantity::new(value, &self.range.unit().to_string())
+    }
+
+    // Ramps with the given ramp until restore_setting puts the
+    // previous setting back. The initial value carries over.
+    fn ramp_temporarily(&mut self, ramp: Ramp) -> SavedSetting {
+        let initial = self.setting.initial_value();
+        let placeholder: Box<dyn Setting> = match initial {
+            Some(initial) => Box::new(DirectSetting::with_initial(initial)),
+            None => Box::new(DirectSetting::new()),
+        };
+        let saved = SavedSetting {
+            setting: std::mem::replace(&mut self.setting, placeholder),
+            ramp: self.ramp,
+        };
+        self.set_ramp(ramp);
+        saved
     }
 
-    pub fn graceful_exit(&self) {
-        todo!("Wait for filament ramp");
-        // for _ in 0..10 {
-        //     println!("Waiting for ramp-down");
-        //     sleep(Duration::from_millis(100));
-        // }
+    fn restore_setting(&mut self, saved: SavedSetting) {
+        self.setting = saved.setting;
+        self.ramp = saved.ramp;
     }
 
-    pub fn update<F>(
-        &mut self,
-        leed_sender: &mpsc::Sender<[u8; 6]>,
-        leed_responses: &Receiver<[u8; 6]>,
-        on_message: F,
-    ) where
-        F: FnMut(Message),
-    {
-        let now = Instant::now();
-        let time_diff = now.duration_since(self.last_current_update);
+    // Replaces the strategy used for reaching the target
+    pub fn set_setting(&mut self, setting: Box<dyn Setting>) {
+        self.setting = setting;
+        self.ramp = None;
+    }
 
-        if time_diff > Duration::from_secs(1) {
-            self.last_current_update = now;
-            // TODO: Send defaults in a better way
-            match self.defaults_counter {
-                0 => {
-                    self.settings.beam_energy.send_default(leed_sender);
-                    self.defaults_counter += 1;
-                }
-                1 => {
-                    self.settings.emission.send_default(leed_sender);
-                    self.defaults_counter += 1;
-                }
-                2 => {
-                    self.settings.suppressor.send_default(leed_sender);
-                    self.defaults_counter += 1;
-                }
-                3 => {
-                    self.settings.screen.send_default(leed_sender);
-                    self.defaults_counter += 1;
-                }
-                4 => {
-                    self.settings.lens2.send_default(leed_sender);
-                    self.defaults_counter += 1;
-                }
-                5 => {
-                    self.settings.lens1_3.send_default(leed_sender);
-                    self.defaults_counter += 1;
-                }
-                _ => {
-                    self.request_currents(leed_sender);
-                }
+    // Ramps towards the target at the given rate, in the units of the control.
+    // Can be changed at any time, also in the middle of a ramp.
+    pub fn set_ramp(&mut self, ramp: Ramp) {
+        let counts_per_second = ramp.rate * self.domain_max as f32 / self.range.span();
+        // The initial value carries over, startup still sends it
+        let setting = match self.setting.initial_value() {
+            Some(initial) => {
+                RampedSetting::with_initial(initial, counts_per_second, ramp.min_interval)
             }
-        }
+            None => RampedSetting::new(counts_per_second, ramp.min_interval),
+        };
+        self.setting = Box::new(setting);
+        self.ramp = Some(ramp);
+    }
 
-        self.settings.update(leed_sender);
-        self.handle_leed_messages(leed_responses, on_message);
+    fn ramped(mut self, ramp: Ramp) -> Self {
+        self.set_ramp(ramp);
+        self
     }
 
-    // Sends a request for ADC values.
-    // The hardware controller will echo the present current values back.
-    fn request_currents(&mut self, sender: &mpsc::Sender<[u8; 6]>) {
-        let tag = match self.adc_counter {
-            0 => Tag::ADC1,
-            1 => Tag::ADC2,
-            _ => Tag::ADC3,
-        };
+    pub fn ramp(&self) -> Option<Ramp> {
+        self.ramp
+    }
 
-        match send_message(tag, 0, sender) {
-            Ok(_) => {
-                self.adc_counter = (self.adc_counter + 1) % 3;
-            }
-            Err(err) => {
-                error!("Request of current failed: {:?}", err);
-            }
+    // Changes the rate of a ramped control, keeping its interval
+    pub fn set_ramp_rate(&mut self, rate: f32) {
+        if let Some(ramp) = self.ramp {
+            self.set_ramp(Ramp { rate, ..ramp });
         }
     }
+}
 
-    pub fn update_from_message(&mut self, msg: Message, log_messages: &mut VecDeque<String>) {
-        let v = msg.value as i32;
-        match &msg.tag {
-            Tag::ADC1 => self.currents.emission = v,
-            Tag::ADC2 => self.currents.beam = v,
-            Tag::ADC3 => self.currents.filament = v,
-            Tag::Control(ctrl) => match ctrl {
-                Control::L2_SET => self.settings.lens2.current_value = v,
-                Control::L13_SET => self.settings.lens1_3.current_value = v,
-                Control::WEH_SET => self.settings.wehnheit.current_value = v,
-                Control::SCR_SET => self.settings.screen.current_value = v,
-                Control::RET_SET_INT => self.settings.suppressor.current_value = v,
-                Control::BEAM_SET_INT => self.settings.beam_energy.current_value = v,
-                Control::EMI_SET => self.settings.emission.current_value = v,
-                Control::IFIL_SET1 => self.settings.filament.current_value = v,
-                Control::EMI_MAX => {
-                    log_messages.push_front(format!("Unhandled LEED message: {:?}", msg.tag))
-                }
-            },
+// Setting and ramp of a control, put aside while it ramps temporarily
+struct SavedSetting {
+    setting: Box<dyn Setting>,
+    ramp: Option<Ramp>,
+}
 
-            _ => log_messages.push_front(format!("Unhandled LEED message: {:?}", msg.tag)),
-        }
+const MAX_DECIMALS: i32 = 6;
+
+fn round_to_decimals(value: f32, decimals: i32) -> f32 {
+    let scale = 10f32.powi(decimals);
+    (value * scale).round() / scale
+}
+
+// Lens offsets, the same for both lenses
+const LENS_OFFSET_MIN: f32 = -20.0;
+const LENS_OFFSET_MAX: f32 = 100.0;
+
+// Lens set to gain * beam energy + offset, like the vendor software does.
+// Gain and offset are the controls, the output follows the beam energy target.
+// A gain of 0 turns the lens off.
+pub struct Lens {
+    gain: f32,
+    offset: f32,
+    max_gain: f32,
+    output: ControlValue,
+}
+
+impl Display for Lens {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "gain {} offset {} {}  -> {}",
+            self.gain,
+            self.offset,
+            self.output.range.unit(),
+            self.output
+        )
     }
+}
 
-    fn handle_leed_messages<F>(
-        &mut self,
-        receiver: &Receiver<[u8; 6]>,
-        mut on_message: F, // ui: &mut UIState,
-    ) where
-        F: FnMut(Message),
-    {
-        while let Ok(buf) = receiver.try_recv() {
-            if let Some(msg) = Message::from_bytes(&buf) {
-                let mut logs = VecDeque::new();
-                self.update_from_message(msg, &mut logs);
-                on_message(msg);
-            }
+impl Lens {
+    fn new(output: ControlValue, max_gain: f32, gain: f32, offset: f32) -> Self {
+        let mut lens = Self {
+            gain: 0.0,
+            offset: 0.0,
+            max_gain,
+            output,
+        };
+        lens.set_gain(gain);
+        lens.set_offset(offset);
+        lens
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    pub fn offset(&self) -> f32 {
+        self.offset
+    }
+
+    pub fn output(&self) -> &ControlValue {
+        &self.output
+    }
+
+    // Clamped to 0..=max gain
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain.clamp(0.0, self.max_gain);
+    }
+
+    // Clamped to the offset range
+    pub fn set_offset(&mut self, offset: f32) {
+        self.offset = offset.clamp(LENS_OFFSET_MIN, LENS_OFFSET_MAX);
+    }
+
+    // Checked versions of set_gain and set_offset, rejecting values out of range
+    pub fn set_gain_target(&mut self, gain: f32, unit: &str) -> Result<(), TargetError> {
+        let name = format!("{} gain", self.output.name);
+        self.gain = checked_value(&name, gain, unit, None, 0.0, self.max_gain)?;
+        Ok(())
+    }
+
+    pub fn set_offset_target(&mut self, offset: f32, unit: &str) -> Result<(), TargetError> {
+        let name = format!("{} offset", self.output.name);
+        self.offset = checked_value(
+            &name,
+            offset,
+            unit,
+            Some(Unit::Volt),
+            LENS_OFFSET_MIN,
+            LENS_OFFSET_MAX,
+        )?;
+        Ok(())
+    }
+
+    pub fn adjust_gain(&mut self, adjustment: Adjustment) {
+        let step = self.max_gain / 500.0;
+        self.set_gain(adjusted(self.gain, step, adjustment));
+    }
+
+    pub fn adjust_offset(&mut self, adjustment: Adjustment) {
+        let step = (LENS_OFFSET_MAX - LENS_OFFSET_MIN) / 500.0;
+        self.set_offset(adjusted(self.offset, step, adjustment));
+    }
+
+    // Output voltage at the beam energy
+    pub fn voltage(&self, beam_energy: f32) -> f32 {
+        self.gain * beam_energy + self.offset
+    }
+
+    fn follow(&mut self, beam_energy: f32) {
+        let raw = if self.gain == 0.0 {
+            0
+        } else {
+            let voltage = self.voltage(beam_energy);
+            self.output.range.to_raw(voltage, self.output.domain_max)
+        };
+        self.output.set_raw_target(raw);
+    }
+
+    fn gain_from_file(
+        &self,
+        file: &SettingsFile,
+        key: &str,
+    ) -> Result<Option<f32>, SettingsFileError> {
+        file.value(key, "")?
+            .map(|gain| check_range(key, gain, 0.0, self.max_gain))
+            .transpose()
+    }
+
+    fn offset_from_file(file: &SettingsFile, key: &str) -> Result<Option<f32>, SettingsFileError> {
+        file_value(file, key, Unit::Volt)?
+            .map(|offset| check_range(key, offset, LENS_OFFSET_MIN, LENS_OFFSET_MAX))
+            .transpose()
+    }
+
+    fn gain_quantity(&self) -> Quantity {
+        Quantity::unitless(round_to_decimals(self.gain, 3))
+    }
+
+    fn offset_quantity(&self) -> Quantity {
+        Quantity::new(round_to_decimals(self.offset, 2), &Unit::Volt.to_string())
+    }
+}
+
+const SUPPRESSOR_MIN_PERCENTAGE: f32 = 10.0;
+const SUPPRESSOR_MAX_PERCENTAGE: f32 = 110.0;
+
+// Suppressor (retarding) voltage set as a percentage of the beam energy.
+// Below 10% the output stays at 10%, as with the vendor software.
+// The output follows the beam energy target like the lenses.
+pub struct Suppressor {
+    percentage: f32,
+    output: ControlValue,
+}
+
+impl Display for Suppressor {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "{} {}  -> {}",
+            self.percentage,
+            Unit::Percentage,
+            self.output
+        )
+    }
+}
+
+impl Suppressor {
+    fn new(output: ControlValue, percentage: f32) -> Self {
+        let mut suppressor = Self {
+            percentage: 0.0,
+            output,
+        };
+        suppressor.set_percentage(percentage);
+        suppressor
+    }
+
+    pub fn percentage(&self) -> f32 {
+        self.percentage
+    }
+
+    pub fn output(&self) -> &ControlValue {
+        &self.output
+    }
+
+    // Clamped to 0..=110%
+    pub fn set_percentage(&mut self, percentage: f32) {
+        self.percentage = percentage.clamp(0.0, SUPPRESSOR_MAX_PERCENTAGE);
+    }
+
+    // Checked version of set_percentage, rejecting values out of range
+    pub fn set_target(&mut self, percentage: f32, unit: &str) -> Result<(), TargetError> {
+        self.percentage = checked_value(
+            &self.output.name,
+            percentage,
+            unit,
+            Some(Unit::Percentage),
+            0.0,
+            SUPPRESSOR_MAX_PERCENTAGE,
+        )?;
+        Ok(())
+    }
+
+    pub fn adjust(&mut self, adjustment: Adjustment) {
+        let step = SUPPRESSOR_MAX_PERCENTAGE / 500.0;
+        self.set_percentage(adjusted(self.percentage, step, adjustment));
+    }
+
+    // Output voltage at the beam energy
+    pub fn voltage(&self, beam_energy: f32) -> f32 {
+        self.percentage.max(SUPPRESSOR_MIN_PERCENTAGE) / 100.0 * beam_energy
+    }
+
+    fn follow(&mut self, beam_energy: f32) {
+        let voltage = self.voltage(beam_energy);
+        let raw = self.output.range.to_raw(voltage, self.output.domain_max);
+        self.output.set_raw_target(raw);
+    }
+
+    fn percentage_from_file(
+        file: &SettingsFile,
+        key: &str,
+    ) -> Result<Option<f32>, SettingsFileError> {
+        file_value(file, key, Unit::Percentage)?
+            .map(|percentage| check_range(key, percentage, 0.0, SUPPRESSOR_MAX_PERCENTAGE))
+            .transpose()
+    }
+
+    fn percentage_quantity(&self) -> Quantity {
+        Quantity::new(
+            round_to_decimals(self.percentage, 2),
+            &Unit::Percentage.to_string(),
+        )
+    }
+}
+
+fn adjusted(value: f32, step: f32, adjustment: Adjustment) -> f32 {
+    match adjustment {
+        Adjustment::Up => value + step,
+        Adjustment::Down => value - step,
+    }
+}
+
+// Entry of a settings file converted to the unit, None when it is missing
+fn file_value(
+    file: &SettingsFile,
+    key: &str,
+    unit: Unit,
+) -> Result<Option<f32>, SettingsFileError> {
+    let Some(quantity) = file.get(key) else {
+        return Ok(None);
+    };
+
+    let symbol = quantity.unit.as_deref().unwrap_or_default();
+    match unit.factor_from(symbol) {
+        Some(factor) => Ok(Some((quantity.value as f64 * factor) as f32)),
+        None => Err(SettingsFileError::WrongUnit {
+            key: key.to_string(),
+            expected: unit.to_string(),
+            found: symbol.to_string(),
+        }),
+    }
+}
+
+fn check_range(key: &str, value: f32, min: f32, max: f32) -> Result<f32, SettingsFileError> {
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(SettingsFileError::OutOfRange {
+            key: key.to_string(),
+            value,
+        })
+    }
+}
+
+// LEED/AUGER and BEAM INT/EXT switches.
+// Sent directly, like a ControlValue with a direct setter.
+pub struct DigOutSetting {
+    pub name: String,
+    pub current_value: DigOutBits,
+    target_value: DigOutBits,
+}
+
+impl Display for DigOutSetting {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "{} [{}]", self.current_value, self.target_value)
+    }
+}
+
+impl DigOutSetting {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            current_value: DigOutBits::default(),
+            target_value: DigOutBits::default(),
+        }
+    }
+
+    fn update(&mut self, link: &mut Transactions) -> Result<(), ProtocolError> {
+        if self.target_value != self.current_value {
+            send_message(Tag::DigOut, self.target_value.0 as i32, link)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn target(&self) -> DigOutBits {
+        self.target_value
+    }
+
+    pub fn set_target(&mut self, bits: DigOutBits) {
+        self.target_value = bits;
+    }
+
+    pub fn toggle_leed_internal(&mut self) {
+        let internal = self.target_value.leed_internal();
+        self.target_value = self.target_value.with_leed_internal(!internal);
+    }
+
+    pub fn toggle_beam_internal(&mut self) {
+        let internal = self.target_value.beam_internal();
+        self.target_value = self.target_value.with_beam_internal(!internal);
+    }
+}
+
+// Last reported value of every ADC monitor channel
+pub struct Currents {
+    pub beam: i32,
+    pub emission: i32,
+    pub filament: i32,
+    pub beam_energy: i32,
+    pub wehnheit: i32,
+    pub screen: i32,
+    pub lens1_3: i32,
+    pub lens2: i32,
+    pub suppressor: i32,
+}
+
+impl Currents {
+    fn new() -> Self {
+        Self {
+            beam: 0,
+            emission: 0,
+            filament: 0,
+            beam_energy: 0,
+            wehnheit: 0,
+            screen: 0,
+            lens1_3: 0,
+            lens2: 0,
+            suppressor: 0,
+        }
+    }
+
+    pub fn get(&self, monitor: Monitor) -> i32 {
+        match monitor {
+            Monitor::L13_MON => self.lens1_3,
+            Monitor::EMI_MON => self.emission,
+            Monitor::L2_MON => self.lens2,
+            Monitor::BEAM_MON => self.beam_energy,
+            Monitor::I0_MON => self.beam,
+            Monitor::RET_MON => self.suppressor,
+            Monitor::SCR_MON => self.screen,
+            Monitor::IFIL_MON => self.filament,
+            Monitor::WEH_MON => self.wehnheit,
+        }
+    }
+
+    pub fn reading(&self, monitor: Monitor) -> Reading {
+        Reading::from_raw(monitor, self.get(monitor))
+    }
+
+    fn set(&mut self, monitor: Monitor, value: i32) {
+        let field = match monitor {
+            Monitor::L13_MON => &mut self.lens1_3,
+            Monitor::EMI_MON => &mut self.emission,
+            Monitor::L2_MON => &mut self.lens2,
+            Monitor::BEAM_MON => &mut self.beam_energy,
+            Monitor::I0_MON => &mut self.beam,
+            Monitor::RET_MON => &mut self.suppressor,
+            Monitor::SCR_MON => &mut self.screen,
+            Monitor::IFIL_MON => &mut self.filament,
+            Monitor::WEH_MON => &mut self.wehnheit,
+        };
+        *field = value;
+    }
+}
+
+pub struct Settings {
+    pub beam_energy: ControlValue,
+    pub wehnheit: ControlValue,
+    pub emission: ControlValue,
+    pub filament: ControlValue,
+    pub screen: ControlValue,
+    pub lens1_3: Lens,
+    pub lens2: Lens,
+    pub suppressor: Suppressor,
+    pub dig_out: DigOutSetting,
+}
+
+impl Settings {
+    // Values startup brings the outputs to, in the order they are sent.
+    // Filament goes first and the screen HV last.
+    fn startup_values(&self) -> Vec<(String, Message)> {
+        let controls = [
+            &self.filament,
+            &self.wehnheit,
+            &self.emission,
+            &self.beam_energy,
+            &self.lens1_3.output,
+            &self.lens2.output,
+            &self.suppressor.output,
+            &self.screen,
+        ];
+
+        let mut values: Vec<(String, Message)> = controls
+            .iter()
+            .map(|control| {
+                let value = control.startup_value();
+                let msg = Message {
+                    tag: Tag::Control(control.control),
+                    value: value as u32,
+                };
+                (control.name.clone(), msg)
+            })
+            .collect();
+
+        values.push((self.dig_out.name.clone(), DigOutBits::default().into()));
+        values
+    }
+
+    // Matches the controller state after a watchdog reset
+    fn outputs_reset(&mut self) {
+        let controls = vec![
+            &mut self.beam_energy,
+            &mut self.wehnheit,
+            &mut self.emission,
+            &mut self.filament,
+            &mut self.screen,
+            &mut self.lens1_3.output,
+            &mut self.lens2.output,
+            &mut self.suppressor.output,
+        ];
+
+        for control in controls {
+            control.current_value = 0;
+        }
+        self.dig_out.current_value = DigOutBits::default();
+    }
+
+    // Lens and suppressor outputs depend on the beam energy,
+    // and are recomputed from its target
+    fn follow_beam_energy(&mut self) {
+        let beam = self.beam_energy.target();
+        self.lens1_3.follow(beam);
+        self.lens2.follow(beam);
+        self.suppressor.follow(beam);
+    }
+
+    fn update(&mut self, link: &mut Transactions) {
+        let controls = vec![
+            &mut self.beam_energy,
+            &mut self.wehnheit,
+            &mut self.emission,
+            &mut self.filament,
+            &mut self.screen,
+            &mut self.lens1_3.output,
+            &mut self.lens2.output,
+            &mut self.suppressor.output,
+        ];
+
+        for control in controls {
+            if let Err(err) = control.update(link) {
+                error!("Failed updating control: {}: {}", control.name, err);
+            }
+        }
+
+        if let Err(err) = self.dig_out.update(link) {
+            error!("Failed updating control: {}: {}", self.dig_out.name, err);
+        }
+    }
+
+    // Makes the echoed values the targets, so update sends nothing new
+    fn hold_current_values(&mut self) {
+        let controls = vec![
+            &mut self.beam_energy,
+            &mut self.wehnheit,
+            &mut self.emission,
+            &mut self.filament,
+            &mut self.screen,
+            &mut self.lens1_3.output,
+            &mut self.lens2.output,
+            &mut self.suppressor.output,
+        ];
+
+        for control in controls {
+            control.target_value = control.current_value;
+        }
+        self.dig_out.target_value = self.dig_out.current_value;
+    }
+}
+
+impl Settings {
+    fn new() -> Self {
+        Self {
+            filament: ControlValue::for_output(
+                "Filament",
+                Box::new(DirectSetting::new()),
+                Control::IFIL_SET1,
+            )
+            .ramped(Ramp::new(0.05, Duration::from_millis(200))),
+            beam_energy: ControlValue::for_output(
+                "Beam energy",
+                Box::new(DirectSetting::with_initial(3500)),
+                Control::BEAM_SET_INT,
+            ),
+            wehnheit: ControlValue::for_output(
+                "Wehnheit",
+                Box::new(DirectSetting::new()),
+                Control::WEH_SET,
+            ),
+            emission: ControlValue::for_output(
+                "Emission",
+                Box::new(DirectSetting::with_initial(16959)),
+                Control::EMI_SET,
+            ),
+            screen: ControlValue::for_output(
+                "Screen",
+                Box::new(DirectSetting::with_initial(63999)),
+                Control::SCR_SET,
+            ),
+            // Lenses:
+            // Offset: -20 - 100V
+            // L2 Gain: 0 - 1.0
+            // L13 Gain: 0 - 2.5
+            // Output value: gain * beam energy + offset
+            lens2: Lens::new(
+                ControlValue::for_output("Lens 2", Box::new(DirectSetting::new()), Control::L2_SET),
+                1.0,
+                0.5,
+                0.0,
+            ),
+            lens1_3: Lens::new(
+                ControlValue::for_output(
+                    "Lens 1/3",
+                    Box::new(DirectSetting::new()),
+                    Control::L13_SET,
+                ),
+                2.5,
+                2.0,
+                11.8,
+            ),
+            // Suppressor: percentage of the beam energy
+            suppressor: Suppressor::new(
+                ControlValue::for_output(
+                    "Suppressor",
+                    Box::new(DirectSetting::new()),
+                    Control::RET_SET_INT,
+                ),
+                80.0,
+            ),
+            dig_out: DigOutSetting::new("Digital outputs"),
+        }
+    }
+}
+
+impl Settings {
+    // Raw targets for a settings file, checked against the units and ranges
+    fn targets_from_file(&self, file: &SettingsFile) -> Result<FileTargets, SettingsFileError> {
+        if let Some((key, _)) = file
+            .entries()
+            .find(|(key, _)| !settings_file::KEYS.contains(key))
+        {
+            return Err(SettingsFileError::UnknownKey(key.to_string()));
+        }
+
+        Ok(FileTargets {
+            beam_energy: self.beam_energy.raw_from_file(file, settings_file::BEAM)?,
+            wehnheit: self.wehnheit.raw_from_file(file, settings_file::WEHNELT)?,
+            filament: self.filament.raw_from_file(file, settings_file::FILAMENT)?,
+            emission: self.emission.raw_from_file(file, settings_file::EMISSION)?,
+            screen: self.screen.raw_from_file(file, settings_file::SCREEN)?,
+            suppressor: Suppressor::percentage_from_file(file, settings_file::SUPPRESSOR)?,
+            lens1_3_gain: self
+                .lens1_3
+                .gain_from_file(file, settings_file::LENS1_GAIN)?,
+            lens1_3_offset: Lens::offset_from_file(file, settings_file::LENS1_OFFSET)?,
+            lens2_gain: self.lens2.gain_from_file(file, settings_file::LENS2_GAIN)?,
+            lens2_offset: Lens::offset_from_file(file, settings_file::LENS2_OFFSET)?,
+        })
+    }
+
+    // Sets the targets given in the file, the others stay as they are.
+    // Nothing changes when any entry is rejected.
+    fn apply_file(&mut self, file: &SettingsFile) -> Result<(), SettingsFileError> {
+        let targets = self.targets_from_file(file)?;
+        self.set_optics_targets(&targets);
+        set_optional_target(&mut self.filament, targets.filament);
+        set_optional_target(&mut self.screen, targets.screen);
+        Ok(())
+    }
+
+    // Everything but the filament and the screen HV
+    fn set_optics_targets(&mut self, targets: &FileTargets) {
+        set_optional_target(&mut self.beam_energy, targets.beam_energy);
+        set_optional_target(&mut self.wehnheit, targets.wehnheit);
+        set_optional_target(&mut self.emission, targets.emission);
+        if let Some(percentage) = targets.suppressor {
+            self.suppressor.set_percentage(percentage);
+        }
+        if let Some(gain) = targets.lens1_3_gain {
+            self.lens1_3.set_gain(gain);
+        }
+        if let Some(offset) = targets.lens1_3_offset {
+            self.lens1_3.set_offset(offset);
+        }
+        if let Some(gain) = targets.lens2_gain {
+            self.lens2.set_gain(gain);
+        }
+        if let Some(offset) = targets.lens2_offset {
+            self.lens2.set_offset(offset);
+        }
+        self.follow_beam_energy();
+    }
+
+    // Everything but filament and screen
+    fn optics(&self) -> [&ControlValue; 6] {
+        [
+            &self.beam_energy,
+            &self.wehnheit,
+            &self.emission,
+            &self.suppressor.output,
+            &self.lens1_3.output,
+            &self.lens2.output,
+        ]
+    }
+
+    fn optics_at_target(&self) -> bool {
+        self.optics().iter().all(|control| control.at_target())
+    }
+
+    // Current targets, in the order of the settings file in the repo root
+    fn to_file(&self) -> SettingsFile {
+        let mut file = SettingsFile::default();
+        file.set(settings_file::BEAM, self.beam_energy.target_quantity());
+        file.set(settings_file::WEHNELT, self.wehnheit.target_quantity());
+        file.set(settings_file::LENS2_OFFSET, self.lens2.offset_quantity());
+        file.set(settings_file::LENS2_GAIN, self.lens2.gain_quantity());
+        file.set(settings_file::LENS1_OFFSET, self.lens1_3.offset_quantity());
+        file.set(settings_file::LENS1_GAIN, self.lens1_3.gain_quantity());
+        file.set(settings_file::FILAMENT, self.filament.target_quantity());
+        file.set(settings_file::EMISSION, self.emission.target_quantity());
+        file.set(settings_file::SCREEN, self.screen.target_quantity());
+        file.set(
+            settings_file::SUPPRESSOR,
+            self.suppressor.percentage_quantity(),
+        );
+        file
+    }
+}
+
+// Raw targets from a settings file, None where it gives no value
+#[derive(Debug, Clone, Default)]
+struct FileTargets {
+    beam_energy: Option<i32>,
+    wehnheit: Option<i32>,
+    filament: Option<i32>,
+    emission: Option<i32>,
+    screen: Option<i32>,
+    suppressor: Option<f32>, // Percentage
+    lens1_3_gain: Option<f32>,
+    lens1_3_offset: Option<f32>,
+    lens2_gain: Option<f32>,
+    lens2_offset: Option<f32>,
+}
+
+fn set_optional_target(control: &mut ControlValue, raw: Option<i32>) {
+    if let Some(raw) = raw {
+        control.set_raw_target(raw);
+    }
+}
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Longest wait for a reply during startup
+const STARTUP_STEP_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StartupFailure {
+    NoStatus,
+    Interlocks(Vec<&'static str>),
+    NoEcho(String),
+    Rejected {
+        name: String,
+        sent: u32,
+        echoed: u32,
+    },
+}
+
+impl Display for StartupFailure {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartupFailure::NoStatus => write!(formatter, "No status from controller"),
+            StartupFailure::Interlocks(faults) => {
+                write!(formatter, "Interlocks: {}", faults.join(", "))
+            }
+            StartupFailure::NoEcho(name) => write!(formatter, "No echo for {}", name),
+            StartupFailure::Rejected { name, sent, echoed } => {
+                write!(formatter, "{} sent {}, echoed {}", name, sent, echoed)
+            }
+        }
+    }
+}
+
+// Steps of the startup sequence, in order.
+// Polling and following targets only begins once Running.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StartupStep {
+    QueryStatus,
+    SendDefault(usize), // Index into Settings::startup_values
+    Running,
+    Failed(StartupFailure),
+}
+
+impl Display for StartupStep {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartupStep::QueryStatus => write!(formatter, "Querying status"),
+            StartupStep::SendDefault(index) => write!(formatter, "Sending default {}", index + 1),
+            StartupStep::Running => write!(formatter, "Running"),
+            StartupStep::Failed(failure) => write!(formatter, "Failed: {}", failure),
+        }
+    }
+}
+
+struct Startup {
+    step: StartupStep,
+    step_started: Instant,
+    pending: Option<(String, Message)>, // Sent, waiting for its echo
+    echo: Option<Message>,
+}
+
+// Filament counts as off below this reported current
+const FILAMENT_OFF_AMPS: f32 = 0.01;
+// Used for the screen during shutdown and preset changes, unless it already ramps
+const SCREEN_RAMP: Ramp = Ramp {
+    rate: 1.0, // kV/s
+    min_interval: Duration::from_millis(200),
+};
+const EMI_MAX_RESEND: Duration = Duration::from_secs(1);
+
+// Steps of the shutdown sequence, in order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownStep {
+    RampFilamentDown,
+    WaitFilamentOff,
+    RampHighVoltageDown,
+    SendEmiMax,
+    Complete,
+}
+
+impl Display for ShutdownStep {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            ShutdownStep::RampFilamentDown => "Ramping filament down",
+            ShutdownStep::WaitFilamentOff => "Waiting for filament current to reach zero",
+            ShutdownStep::RampHighVoltageDown => "Bringing screen and beam energy down",
+            ShutdownStep::SendEmiMax => "Sending EMI_MAX",
+            ShutdownStep::Complete => "Shutdown complete",
+        };
+        write!(formatter, "{}", description)
+    }
+}
+
+struct Shutdown {
+    step: ShutdownStep,
+    step_started: Instant,
+    // Startup had not completed, so the targets were never all sent.
+    // The sequence starts from the echoed values and only lowers outputs.
+    from_echoes: bool,
+    queue_dropped: bool,
+}
+
+// Steps of a change to a preset, in order. Each waits for the echoed values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransitionStep {
+    LowerScreen, // To zero, filament and optics never change under high voltage
+    Filament,
+    Optics, // Everything but filament and screen
+    RaiseScreen,
+}
+
+impl Display for TransitionStep {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            TransitionStep::LowerScreen => "Lowering screen voltage",
+            TransitionStep::Filament => "Ramping filament",
+            TransitionStep::Optics => "Setting beam, lenses and suppressor",
+            TransitionStep::RaiseScreen => "Raising screen voltage",
+        };
+        write!(formatter, "{}", description)
+    }
+}
+
+// Allowed for a transition step on top of the time its ramps take
+const TRANSITION_STEP_MARGIN: Duration = Duration::from_secs(5);
+
+struct Transition {
+    preset: String,
+    step: TransitionStep,
+    step_started: Instant,
+    step_timeout: Duration,
+    targets: FileTargets,
+    screen_setting: Option<SavedSetting>, // Put back when the transition ends
+}
+
+pub struct LEEDController {
+    pub currents: Currents, // Received from controller hardware
+    pub settings: Settings,
+    pub status: Option<StatusBits>, // Last status reported by controller hardware
+    pub errors: ErrorCounts,        // Protocol errors in received frames
+    pub connection: ConnectionState,
+    last_current_update: Instant,
+    last_status_request: Option<Instant>,
+    started: bool, // Has sent a frame to the controller
+    startup: Startup,
+    status_at: Option<Instant>,
+    shutdown: Option<Shutdown>,
+    transition: Option<Transition>,
+    filament_reading_at: Option<Instant>,
+    emi_max_echo_at: Option<Instant>,
+}
+
+impl Drop for LEEDController {
+    fn drop(&mut self) {
+        if self.started && !self.shutdown_complete() {
+            warn!(
+                "LEED controller dropped before shutdown completed, outputs left to the watchdog"
+            );
+        }
+    }
+}
+
+impl LEEDController {
+    pub fn new() -> Self {
+        Self {
+            currents: Currents::new(),
+            settings: Settings::new(),
+            status: None,
+            errors: ErrorCounts::default(),
+            connection: ConnectionState::Connected,
+            last_current_update: Instant::now(),
+            last_status_request: None,
+            started: false,
+            startup: Startup {
+                step: StartupStep::QueryStatus,
+                step_started: Instant::now(),
+                pending: None,
+                echo: None,
+            },
+            status_at: None,
+            shutdown: None,
+            transition: None,
+            filament_reading_at: None,
+            emi_max_echo_at: None,
+        }
+    }
+
+    // Sets the targets given in a settings file. Before startup has finished they
+    // are sent by startup, later the controls move to them as set up, ramped controls ramping.
+    pub fn apply_settings_file(&mut self, file: &SettingsFile) -> Result<(), SettingsFileError> {
+        self.settings.apply_file(file)?;
+        info!("Applied settings file");
+        Ok(())
+    }
+
+    pub fn load_settings_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SettingsFileError> {
+        let file = SettingsFile::load(&path)?;
+        self.apply_settings_file(&file)?;
+        info!("Loaded settings from {}", path.as_ref().display());
+        Ok(())
+    }
+
+    // Sets a target in physical units, the control named by its settings file key:
+    // set_target("beam", 120.0, "eV"), set_target("screen", 7000.0, "V").
+    // Gains take an empty unit.
+    pub fn set_target(&mut self, key: &str, value: f32, unit: &str) -> Result<(), TargetError> {
+        if self.shutdown.is_some() {
+            return Err(TargetError::ShuttingDown);
+        }
+
+        let settings = &mut self.settings;
+        match key {
+            settings_file::BEAM => settings.beam_energy.set_target(value, unit),
+            settings_file::WEHNELT => settings.wehnheit.set_target(value, unit),
+            settings_file::FILAMENT => settings.filament.set_target(value, unit),
+            settings_file::EMISSION => settings.emission.set_target(value, unit),
+            settings_file::SCREEN => settings.screen.set_target(value, unit),
+            settings_file::SUPPRESSOR => settings.suppressor.set_target(value, unit),
+            settings_file::LENS1_GAIN => settings.lens1_3.set_gain_target(value, unit),
+            settings_file::LENS1_OFFSET => settings.lens1_3.set_offset_target(value, unit),
+            settings_file::LENS2_GAIN => settings.lens2.set_gain_target(value, unit),
+            settings_file::LENS2_OFFSET => settings.lens2.set_offset_target(value, unit),
+            _ => Err(TargetError::UnknownControl(key.to_string())),
+        }
+    }
+
+    // Current targets as a settings file
+    pub fn settings_file(&self) -> SettingsFile {
+        self.settings.to_file()
+    }
+
+    pub fn save_settings_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SettingsFileError> {
+        self.settings_file().save(&path)?;
+        info!("Saved settings to {}", path.as_ref().display());
+        Ok(())
+    }
+
+    pub fn startup_step(&self) -> &StartupStep {
+        &self.startup.step
+    }
+
+    // Runs startup again from the top, after a failure or a reconnect
+    pub fn restart_startup(&mut self) {
+        info!("Startup: {}", StartupStep::QueryStatus);
+        self.enter_startup_step(StartupStep::QueryStatus);
+    }
+
+    fn enter_startup_step(&mut self, step: StartupStep) {
+        match &step {
+            StartupStep::Failed(failure) => error!("Startup failed: {}", failure),
+            StartupStep::Running => info!("Startup complete"),
+            _ => {}
+        }
+
+        self.startup = Startup {
+            step,
+            step_started: Instant::now(),
+            pending: None,
+            echo: None,
+        };
+    }
+
+    fn update_startup(&mut self, link: &mut Transactions) {
+        let timed_out = self.startup.step_started.elapsed() > STARTUP_STEP_TIMEOUT;
+
+        let next = match &self.startup.step {
+            StartupStep::QueryStatus => {
+                if self.startup.pending.is_none() {
+                    self.request_status(link);
+                    self.startup.pending = Some((
+                        "Status".to_string(),
+                        Message {
+                            tag: Tag::Status,
+                            value: 0,
+                        },
+                    ));
+                }
+
+                let fresh = matches!(self.status_at, Some(at) if at > self.startup.step_started);
+                match self.status {
+                    Some(status) if fresh => {
+                        let faults = status.faults();
+                        if faults.is_empty() {
+                            Some(StartupStep::SendDefault(0))
+                        } else {
+                            Some(StartupStep::Failed(StartupFailure::Interlocks(faults)))
+                        }
+                    }
+                    _ if timed_out => Some(StartupStep::Failed(StartupFailure::NoStatus)),
+                    _ => None,
+                }
+            }
+            StartupStep::SendDefault(index) => {
+                let index = *index;
+                match (&self.startup.pending, self.startup.echo) {
+                    (None, _) => {
+                        let values = self.settings.startup_values();
+                        match values.into_iter().nth(index) {
+                            Some((name, msg)) => {
+                                info!("Startup: {} = {}", name, msg.value);
+                                if let Err(err) = link.request(msg) {
+                                    error!("Startup request failed: {}", err);
+                                }
+                                self.startup.pending = Some((name, msg));
+                                None
+                            }
+                            None => Some(StartupStep::Running),
+                        }
+                    }
+                    (Some((name, sent)), Some(echo)) => {
+                        if echo.value == sent.value {
+                            Some(StartupStep::SendDefault(index + 1))
+                        } else {
+                            Some(StartupStep::Failed(StartupFailure::Rejected {
+                                name: name.clone(),
+                                sent: sent.value,
+                                echoed: echo.value,
+                            }))
+                        }
+                    }
+                    (Some((name, _)), None) if timed_out => {
+                        Some(StartupStep::Failed(StartupFailure::NoEcho(name.clone())))
+                    }
+                    (Some(_), None) => None,
+                }
+            }
+            StartupStep::Running | StartupStep::Failed(_) => None,
+        };
+
+        if let Some(step) = next {
+            self.enter_startup_step(step);
+        }
+    }
+
+    // Replies during startup, matched against the default being sent
+    fn startup_reply(&mut self, request: Message, reply: Message) {
+        if let Some((_, pending)) = &self.startup.pending {
+            if pending.tag == request.tag
+                && matches!(self.startup.step, StartupStep::SendDefault(_))
+            {
+                self.startup.echo = Some(reply);
+            }
+        }
+    }
+
+    // Starts the shutdown sequence, driven by update from here on.
+    // Targets are taken over by the sequence, adjustments have no effect.
+    // Before startup has completed, the targets are dropped for the echoed
+    // values, so defaults which were never accepted are not sent now.
+    pub fn graceful_exit(&mut self) {
+        if self.shutdown.is_none() {
+            self.end_transition();
+            let from_echoes = self.startup.step != StartupStep::Running;
+            if from_echoes {
+                info!("Shutdown before startup completed, holding the echoed values");
+                self.settings.hold_current_values();
+            }
+            info!("Shutdown: {}", ShutdownStep::RampFilamentDown);
+            self.shutdown = Some(Shutdown {
+                step: ShutdownStep::RampFilamentDown,
+                step_started: Instant::now(),
+                from_echoes,
+                queue_dropped: false,
+            });
+        }
+    }
+
+    // Current step, None unless shutting down
+    pub fn shutdown_step(&self) -> Option<ShutdownStep> {
+        self.shutdown.as_ref().map(|shutdown| shutdown.step)
+    }
+
+    // True once the filament is confirmed off and the high voltages are down.
+    // Also true if nothing was ever sent, then there is nothing to shut down.
+    pub fn shutdown_complete(&self) -> bool {
+        !self.started || self.shutdown_step() == Some(ShutdownStep::Complete)
+    }
+
+    // What the current shutdown step is waiting for, None unless shutting down.
+    // The sequence only moves on while the link is up.
+    pub fn shutdown_waiting_for(&self) -> Option<String> {
+        let shutdown = self.shutdown.as_ref()?;
+        if self.connection != ConnectionState::Connected {
+            return Some(format!("link {}", self.connection));
+        }
+
+        let settings = &self.settings;
+        let reason = match shutdown.step {
+            ShutdownStep::RampFilamentDown => format!(
+                "filament setpoint {:.3} {}",
+                settings.filament.current(),
+                settings.filament.range.unit()
+            ),
+            ShutdownStep::WaitFilamentOff => {
+                let fresh =
+                    matches!(self.filament_reading_at, Some(at) if at > shutdown.step_started);
+                if fresh {
+                    let reading = self.monitor_reading(Monitor::IFIL_MON);
+                    format!("filament current {:.3} {}", reading.value, reading.unit)
+                } else {
+                    "filament current reading".to_string()
+                }
+            }
+            ShutdownStep::RampHighVoltageDown => format!(
+                "screen {:.2} {}, beam energy {:.1} {}",
+                settings.screen.current(),
+                settings.screen.range.unit(),
+                settings.beam_energy.current(),
+                settings.beam_energy.range.unit()
+            ),
+            ShutdownStep::SendEmiMax => "EMI_MAX echo".to_string(),
+            ShutdownStep::Complete => return None,
+        };
+        Some(reason)
+    }
+
+    fn update_shutdown(&mut self, link: &mut Transactions) {
+        let Some(shutdown) = &mut self.shutdown else {
+            return;
+        };
+        if !shutdown.queue_dropped {
+            // Queued setpoints and startup defaults are superseded by the sequence
+            let dropped = link.drop_queued();
+            if dropped > 0 {
+                info!("Shutdown: dropped {} queued requests", dropped);
+            }
+            shutdown.queue_dropped = true;
+        }
+        let step_started = shutdown.step_started;
+
+        let next = match shutdown.step {
+            ShutdownStep::RampFilamentDown => {
+                self.settings.filament.set_raw_target(0);
+                (self.settings.filament.current_value == 0).then_some(ShutdownStep::WaitFilamentOff)
+            }
+            ShutdownStep::WaitFilamentOff => {
+                let fresh = matches!(self.filament_reading_at, Some(at) if at > step_started);
+                let amps = self.monitor_reading(Monitor::IFIL_MON).value;
+                (fresh && amps <= FILAMENT_OFF_AMPS).then_some(ShutdownStep::RampHighVoltageDown)
+            }
+            ShutdownStep::RampHighVoltageDown => {
+                let down = self.settings.screen.current_value == 0
+                    && self.settings.beam_energy.current_value == 0;
+                down.then_some(ShutdownStep::SendEmiMax)
+            }
+            // EMI_MAX = 0, as the vendor software ends its shutdown
+            ShutdownStep::SendEmiMax => {
+                if matches!(self.emi_max_echo_at, Some(at) if at > step_started) {
+                    Some(ShutdownStep::Complete)
+                } else {
+                    if step_started.elapsed() > EMI_MAX_RESEND {
+                        self.send_emi_max(link);
+                    }
+                    None
+                }
+            }
+            ShutdownStep::Complete => None,
+        };
+
+        if let Some(step) = next {
+            info!("Shutdown: {}", step);
+            if let Some(shutdown) = &mut self.shutdown {
+                shutdown.step = step;
+                shutdown.step_started = Instant::now();
+            }
+            self.enter_shutdown_step(step, link);
+        }
+    }
+
+    fn enter_shutdown_step(&mut self, step: ShutdownStep, link: &mut Transactions) {
+        match step {
+            ShutdownStep::RampHighVoltageDown => {
+                if self.settings.screen.ramp().is_none() {
+                    self.settings.screen.set_ramp(SCREEN_RAMP);
+                }
+                self.settings.screen.set_raw_target(0);
+                self.settings.beam_energy.set_raw_target(0);
+            }
+            ShutdownStep::SendEmiMax => self.send_emi_max(link),
+            _ => {}
+        }
+    }
+
+    // The vendor software sends EMI_MAX with value 0 as the last frame on
+    // shutdown, captured in its traces; the same frame is sent here.
+    fn send_emi_max(&mut self, link: &mut Transactions) {
+        if let Some(shutdown) = &mut self.shutdown {
+            shutdown.step_started = Instant::now();
+        }
+        if let Err(err) = send_message(Tag::Control(Control::EMI_MAX), 0, link) {
+            error!("Sending EMI_MAX failed: {}", err);
+        }
+    }
+
+    // Moves to the preset in a safe order: screen HV down, filament, the other
+    // outputs, screen HV up. Filament and screen ramp, the screen with
+    // SCREEN_RAMP unless it has its own ramp, until the change ends.
+    // The screen stays up when the preset keeps it where it is.
+    // Replaces a running change.
+    // A preset without a screen entry raises the screen back to where it was.
+    pub fn select_preset(&mut self, preset: &Preset) -> Result<(), PresetError> {
+        if self.shutdown.is_some() {
+            return Err(PresetError::ShuttingDown);
+        }
+        if self.startup.step != StartupStep::Running {
+            return Err(PresetError::StartupIncomplete);
+        }
+
+        let mut targets = self.settings.targets_from_file(&preset.settings)?;
+        let replaced = self.transition.take();
+        let screen_before = replaced
+            .as_ref()
+            .and_then(|transition| transition.targets.screen)
+            .unwrap_or(self.settings.screen.target_value);
+        let screen_target = *targets.screen.get_or_insert(screen_before);
+
+        let mut screen_setting = replaced.and_then(|transition| transition.screen_setting);
+        if screen_setting.is_none() && self.settings.screen.ramp().is_none() {
+            screen_setting = Some(self.settings.screen.ramp_temporarily(SCREEN_RAMP));
+        }
+
+        let step = if screen_target == screen_before {
+            TransitionStep::Filament
+        } else {
+            TransitionStep::LowerScreen
+        };
+        info!("Preset {}: {}", preset.name, step);
+        self.transition = Some(Transition {
+            preset: preset.name.clone(),
+            step,
+            step_started: Instant::now(),
+            step_timeout: TRANSITION_STEP_MARGIN,
+            targets,
+            screen_setting,
+        });
+        self.enter_transition_step(step);
+        Ok(())
+    }
+
+    // Puts the screen setting back. A screen still ramping is held
+    // where it is, the restored setting might jump to the target.
+    fn end_transition(&mut self) {
+        let Some(transition) = self.transition.take() else {
+            return;
+        };
+        let screen = &mut self.settings.screen;
+        if !screen.at_target() {
+            warn!(
+                "Screen held at {:.2} {}",
+                screen.current(),
+                screen.range.unit()
+            );
+            screen.target_value = screen.current_value;
+        }
+        if let Some(saved) = transition.screen_setting {
+            screen.restore_setting(saved);
+        }
+    }
+
+    // Preset being moved to and the current step, None when not changing presets
+    pub fn transition(&self) -> Option<(&str, TransitionStep)> {
+        self.transition
+            .as_ref()
+            .map(|transition| (transition.preset.as_str(), transition.step))
+    }
+
+    fn enter_transition_step(&mut self, step: TransitionStep) {
+        let Some(transition) = &mut self.transition else {
+            return;
+        };
+        let settings = &mut self.settings;
+
+        let targets = &transition.targets;
+        let ramp_duration = match step {
+            TransitionStep::LowerScreen => {
+                settings.screen.set_raw_target(0);
+                settings.screen.ramp_duration()
+            }
+            TransitionStep::Filament => {
+                set_optional_target(&mut settings.filament, targets.filament);
+                settings.filament.ramp_duration()
+            }
+            TransitionStep::Optics => {
+                settings.set_optics_targets(targets);
+                settings
+                    .optics()
+                    .iter()
+                    .map(|control| control.ramp_duration())
+                    .max()
+                    .unwrap_or(Duration::ZERO)
+            }
+            TransitionStep::RaiseScreen => {
+                set_optional_target(&mut settings.screen, targets.screen);
+                settings.screen.ramp_duration()
+            }
+        };
+
+        transition.step_started = Instant::now();
+        transition.step_timeout = ramp_duration.saturating_add(TRANSITION_STEP_MARGIN);
+    }
+
+    fn update_transition(&mut self) {
+        let Some(transition) = &mut self.transition else {
+            return;
+        };
+
+        let settings = &self.settings;
+        let reached = match transition.step {
+            TransitionStep::LowerScreen | TransitionStep::RaiseScreen => {
+                settings.screen.at_target()
+            }
+            TransitionStep::Filament => settings.filament.at_target(),
+            TransitionStep::Optics => settings.optics_at_target(),
+        };
+        if !reached {
+            if transition.step_started.elapsed() > transition.step_timeout {
+                error!(
+                    "Preset {} aborted: {} took longer than {:?}",
+                    transition.preset, transition.step, transition.step_timeout
+                );
+                self.end_transition();
+            }
+            return;
+        }
+
+        let next = match transition.step {
+            TransitionStep::LowerScreen => TransitionStep::Filament,
+            TransitionStep::Filament => TransitionStep::Optics,
+            TransitionStep::Optics => TransitionStep::RaiseScreen,
+            TransitionStep::RaiseScreen => {
+                info!("Preset {} reached", transition.preset);
+                self.end_transition();
+                return;
+            }
+        };
+
+        info!("Preset {}: {}", transition.preset, next);
+        transition.step = next;
+        self.enter_transition_step(next);
+    }
+
+    // Call with the state published by the monitor thread.
+    // The controller watchdog resets all outputs while the link is down,
+    // so after reconnecting startup runs again, and the settings follow
+    // their targets again ramping from zero.
+    pub fn set_connection(&mut self, state: ConnectionState) {
+        if state == self.connection {
+            return;
+        }
+
+        info!("LEED link {}", state);
+        if state == ConnectionState::Connected {
+            self.settings.outputs_reset();
+            self.status = None;
+            self.last_status_request = None;
+            self.restart_startup();
+        }
+        self.connection = state;
+    }
+
+    pub fn update<F>(&mut self, link: &mut Transactions, on_message: F)
+    where
+        F: FnMut(Message),
+    {
+        if self.connection != ConnectionState::Connected {
+            self.handle_link_events(link, on_message);
+            return;
+        }
+
+        let now = Instant::now();
+        let time_diff = now.duration_since(self.last_current_update);
+
+        // Without a completed startup the lens and suppressor targets were never
+        // sent, following the beam energy down would send them now
+        if !self
+            .shutdown
+            .as_ref()
+            .is_some_and(|shutdown| shutdown.from_echoes)
+        {
+            self.settings.follow_beam_energy();
+        }
+
+        if self.shutdown.is_none() && self.startup.step != StartupStep::Running {
+            self.update_startup(link);
+            self.handle_link_events(link, on_message);
+            return;
+        }
+
+        self.update_shutdown(link);
+        self.update_transition();
+
+        if time_diff > Duration::from_secs(1) {
+            self.last_current_update = now;
+            let status_due = match self.last_status_request {
+                Some(last) => now.duration_since(last) > STATUS_INTERVAL,
+                None => true,
+            };
+            if self.shutdown_step() == Some(ShutdownStep::WaitFilamentOff) {
+                // Only the filament current matters now
+                if let Err(err) = send_message(Tag::Monitor(Monitor::IFIL_MON), 0, link) {
+                    error!("Request of filament current failed: {}", err);
+                }
+            } else {
+                if status_due {
+                    self.request_status(link);
+                }
+                self.request_currents(link);
+            }
+        }
+
+        // Running, or shutting down. A shutdown before startup completed
+        // holds the echoed values, so this only sends the outputs it lowers.
+        self.settings.update(link);
+        self.handle_link_events(link, on_message);
+    }
+
+    // Sends a request for ADC values of every monitor channel, so each one
+    // is refreshed once per update interval.
+    // The hardware controller will echo the present monitor values back.
+    fn request_currents(&mut self, link: &mut Transactions) {
+        for monitor in Monitor::ALL {
+            if let Err(err) = send_message(Tag::Monitor(monitor), 0, link) {
+                error!("Request of current failed: {}", err);
+            }
+        }
+    }
+
+    // Last reported value of a monitor channel, in physical units
+    pub fn monitor_reading(&self, monitor: Monitor) -> Reading {
+        self.currents.reading(monitor)
+    }
+
+    // Suppressor readback relative to the beam energy readback, like its setpoint.
+    // None while there is no beam energy.
+    pub fn suppressor_reading_percentage(&self) -> Option<f32> {
+        let beam_energy = self.monitor_reading(Monitor::BEAM_MON).value;
+        let voltage = self.monitor_reading(Monitor::RET_MON).value;
+        (beam_energy > 0.0).then(|| voltage / beam_energy * 100.0)
+    }
+
+    fn request_status(&mut self, link: &mut Transactions) {
+        match send_message(Tag::Status, 0, link) {
+            Ok(_) => {
+                self.last_status_request = Some(Instant::now());
+            }
+            Err(err) => {
+                error!("Request of status failed: {}", err);
+            }
+        }
+    }
+
+    pub fn update_from_message(&mut self, msg: Message, log_messages: &mut VecDeque<String>) {
+        let v = msg.value as i32;
+        match &msg.tag {
+            Tag::Monitor(monitor) => {
+                if *monitor == Monitor::IFIL_MON {
+                    self.filament_reading_at = Some(Instant::now());
+                }
+                self.currents.set(*monitor, v)
+            }
+            Tag::Status => {
+                self.status = msg.status();
+                self.status_at = Some(Instant::now());
+            }
+            Tag::DigOut => self.settings.dig_out.current_value = DigOutBits(msg.value as u8),
+            Tag::Unknown(_) => {
+                log_messages.push_front(format!("Unhandled LEED message: {:?}", msg))
+            }
+            Tag::Control(ctrl) => match ctrl {
+                Control::L2_SET => self.settings.lens2.output.read_back(v),
+                Control::L13_SET => self.settings.lens1_3.output.read_back(v),
+                Control::WEH_SET => self.settings.wehnheit.read_back(v),
+                Control::SCR_SET => self.settings.screen.read_back(v),
+                Control::RET_SET_INT => self.settings.suppressor.output.read_back(v),
+                Control::BEAM_SET_INT => self.settings.beam_energy.read_back(v),
+                Control::EMI_SET => self.settings.emission.read_back(v),
+                Control::IFIL_SET1 => self.settings.filament.read_back(v),
+                Control::EMI_MAX => self.emi_max_echo_at = Some(Instant::now()),
+            },
+        }
+    }
+
+    fn handle_link_events<F>(&mut self, link: &mut Transactions, mut on_message: F)
+    where
+        F: FnMut(Message),
+    {
+        let events = match link.poll() {
+            Ok(events) => events,
+            Err(err) => {
+                error!("LEED link closed: {:?}", err);
+                return;
+            }
+        };
+        self.started |= link.stats().sent > 0;
+
+        let mut logs = VecDeque::new();
+        for event in events {
+            match event {
+                Event::Reply { request, reply, .. } => {
+                    let is_setpoint = matches!(request.tag, Tag::Control(_) | Tag::DigOut);
+                    if is_setpoint && reply.value != request.value {
+                        warn!(
+                            "{:?} not accepted: requested {}, echoed {}",
+                            request.tag, request.value, reply.value
+                        );
+                    }
+                    self.startup_reply(request, reply);
+                    self.update_from_message(reply, &mut logs);
+                    on_message(reply);
+                }
+                Event::Unsolicited(msg) => {
+                    self.update_from_message(msg, &mut logs);
+                    on_message(msg);
+                }
+                Event::TimedOut { request, attempts } => {
+                    error!("No reply to {:?} after {} attempts", request, attempts);
+                }
+                Event::Invalid(buf, err) => {
+                    error!("Invalid LEED message {:02X?}: {}", buf, err);
+                    self.errors.record(&err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::preset::PresetLibrary;
+    use crate::common::simulator::Simulator;
+    use crate::common::transaction::RetryPolicy;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // The vendor software rounds differently, values measured with it
+    // may be one count off
+    fn assert_counts(actual: i32, measured: i32) {
+        assert!(
+            (actual - measured).abs() <= 1,
+            "{} counts, measured {}",
+            actual,
+            measured
+        );
+    }
+
+    // Beam energy and lens DAC values as measured in docs/notes.wiki
+    fn lens_at(lens: &mut Lens, beam: &mut ControlValue, beam_raw: i32) -> i32 {
+        beam.set_raw_target(beam_raw);
+        lens.follow(beam.target());
+        lens.output.target_value
+    }
+
+    #[test]
+    fn lens1_3_matches_notes() {
+        let mut settings = Settings::new();
+        let lens = &mut settings.lens1_3;
+        let beam = &mut settings.beam_energy;
+
+        lens.set_gain(2.5);
+        lens.set_offset(0.0);
+        assert_counts(lens_at(lens, beam, 6399), 5721);
+        assert_counts(lens_at(lens, beam, 31999), 26913);
+        assert_counts(lens_at(lens, beam, 63999), 53403);
+
+        lens.set_gain(1.0);
+        assert_counts(lens_at(lens, beam, 6399), 2543);
+    }
+
+    #[test]
+    fn lens2_matches_notes() {
+        let mut settings = Settings::new();
+        let lens = &mut settings.lens2;
+        let beam = &mut settings.beam_energy;
+
+        lens.set_gain(0.5);
+        lens.set_offset(0.0);
+        assert_counts(lens_at(lens, beam, 63999), 11019);
+
+        lens.set_offset(-20.0);
+        assert_counts(lens_at(lens, beam, 63999), 10595);
+        assert_counts(lens_at(lens, beam, 12799), 2119);
+    }
+
+    #[test]
+    fn lens_follows_beam_energy_target() {
+        let mut settings = Settings::new();
+        settings.lens2.set_gain(0.5);
+        settings.lens2.set_offset(0.0);
+
+        settings.beam_energy.set_raw_target(63999);
+        settings.follow_beam_energy();
+        assert_eq!(settings.lens2.output.target_value, 11019);
+
+        settings.beam_energy.set_raw_target(12799);
+        settings.follow_beam_energy();
+        assert_eq!(settings.lens2.output.target_value, 2543);
+    }
+
+    // A monitor reading back the DAC count of its setpoint shows the setpoint value
+    #[test]
+    fn beam_current_monitor_is_uncalibrated() {
+        let reading = Reading::from_raw(Monitor::I0_MON, ADC_MAX);
+        assert_eq!(reading.unit, Unit::Volt);
+        assert_eq!(reading.value, ADC_FULL_SCALE_VOLTS);
+        assert!(!reading.calibrated);
+        assert!(reading.to_string().contains("uncalibrated"));
+        assert!(Reading::from_raw(Monitor::EMI_MON, 0).calibrated);
+    }
+
+    #[test]
+    fn monitors_read_back_setpoints() {
+        let settings = Settings::new();
+        let outputs = [
+            (Monitor::L13_MON, settings.lens1_3.output()),
+            (Monitor::L2_MON, settings.lens2.output()),
+            (Monitor::RET_MON, settings.suppressor.output()),
+            (Monitor::BEAM_MON, &settings.beam_energy),
+            (Monitor::EMI_MON, &settings.emission),
+            (Monitor::IFIL_MON, &settings.filament),
+            (Monitor::SCR_MON, &settings.screen),
+            (Monitor::WEH_MON, &settings.wehnheit),
+        ];
+        for (monitor, output) in outputs {
+            for raw in [0, output.domain_max / 3, output.domain_max] {
+                let setpoint = output.range.to_physical(raw, output.domain_max);
+                let reading = Reading::from_raw(monitor, raw);
+                assert_eq!(reading.unit, output.range.unit());
+                assert!(
+                    (reading.value - setpoint).abs() <= setpoint.abs() * 1e-4 + 1e-4,
+                    "{:?} at {}: {} vs {}",
+                    monitor,
+                    raw,
+                    reading.value,
+                    setpoint
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lens_gain_zero_is_off() {
+        let mut settings = Settings::new();
+        settings.lens1_3.set_gain(0.0);
+        settings.lens1_3.set_offset(50.0);
+        assert_eq!(
+            lens_at(&mut settings.lens1_3, &mut settings.beam_energy, 63999),
+            0
+        );
+    }
+
+    // Suppressor setting, beam energy and RET_SET_INT as measured in docs/notes.wiki
+    #[test]
+    fn suppressor_matches_notes() {
+        let measured = [
+            (0.0, 63999, 3199),
+            (0.0, 31999, 1599),
+            (0.0, 15999, 799),
+            (60.0, 63999, 19199),
+            (60.0, 31999, 9599),
+            (55.0, 63999, 17599),
+            (50.0, 63999, 15999),
+            (50.0, 31999, 7999),
+            (100.0, 31999, 15999),
+            (100.0, 63999, 31999),
+            (110.0, 31999, 17599),
+            (110.0, 63999, 35199),
+        ];
+
+        let mut settings = Settings::new();
+        for (percentage, beam_raw, ret_raw) in measured {
+            settings.suppressor.set_percentage(percentage);
+            settings.beam_energy.set_raw_target(beam_raw);
+            settings.follow_beam_energy();
+            assert_counts(settings.suppressor.output.target_value, ret_raw);
+        }
+    }
+
+    #[test]
+    fn suppressor_is_lowest_without_beam_energy() {
+        let mut settings = Settings::new();
+        settings.suppressor.set_percentage(110.0);
+        settings.beam_energy.set_raw_target(0);
+        settings.follow_beam_energy();
+        assert_eq!(settings.suppressor.output.target_value, 0);
+    }
+
+    #[test]
+    fn set_target_converts_prefixes() -> Result<(), TargetError> {
+        let mut settings = Settings::new();
+        settings.screen.set_target(7000.0, "V")?;
+        assert_eq!(settings.screen.target_value, 63999);
+        settings.screen.set_target(3.5, "kV")?;
+        assert_eq!(settings.screen.target_value, 32000);
+        settings.emission.set_target(0.025, "mA")?;
+        assert_eq!(settings.emission.target_value, 8480);
+        settings.beam_energy.set_target(120.0, "")?;
+        assert_eq!(settings.beam_energy.target_value, 7680);
+        Ok(())
+    }
+
+    #[test]
+    fn set_target_inverts_min_max_range() -> Result<(), TargetError> {
+        let mut settings = Settings::new();
+        let output = &mut settings.lens2.output;
+        for volts in [-20.0, 0.0, 479.97473, 1100.0] {
+            output.set_target(volts, "V")?;
+            assert!((output.target() - volts).abs() < 0.05, "{} V", volts);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn set_target_rejects_out_of_range() -> Result<(), TargetError> {
+        let mut settings = Settings::new();
+        settings.screen.set_target(5.0, "kV")?;
+        let target = settings.screen.target_value;
+
+        assert!(matches!(
+            settings.screen.set_target(7.5, "kV"),
+            Err(TargetError::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            settings.screen.set_target(5.0, "A"),
+            Err(TargetError::WrongUnit { .. })
+        ));
+        assert_eq!(settings.screen.target_value, target);
+        Ok(())
+    }
+
+    #[test]
+    fn repo_settings_file_round_trips() -> Result<(), SettingsFileError> {
+        let repo = SettingsFile::parse(include_str!("../../settings"))?;
+        let mut controller = LEEDController::new();
+        controller.apply_settings_file(&repo)?;
+        let saved = controller.settings_file();
+
+        // Saved with units, the repo file leaves some out
+        for (key, quantity) in repo.entries() {
+            let saved = saved.get(key).expect("Every key is saved");
+            assert!((saved.value - quantity.value).abs() < 1e-3, "{}", key);
+            assert!(
+                quantity.unit.is_none() || quantity.unit == saved.unit,
+                "{}",
+                key
+            );
+        }
+
+        let mut reloaded = LEEDController::new();
+        reloaded.apply_settings_file(&SettingsFile::parse(&saved.to_string())?)?;
+        assert_eq!(reloaded.settings_file(), saved);
+        Ok(())
+    }
+
+    #[test]
+    fn settings_file_rejects_unknown_keys_and_units() -> Result<(), SettingsFileError> {
+        let mut controller = LEEDController::new();
+        let before = controller.settings_file();
+
+        for text in ["beam: 50 eV\nfocus: 3 V\n", "beam: 50 eV\nscreen: 7 A\n"] {
+            let result = controller.apply_settings_file(&SettingsFile::parse(text)?);
+            assert!(
+                matches!(
+                    result,
+                    Err(SettingsFileError::UnknownKey(_) | SettingsFileError::WrongUnit { .. })
+                ),
+                "{:?}",
+                text
+            );
+        }
+        // Nothing is applied from a rejected file
+        assert_eq!(controller.settings_file(), before);
+
+        controller.apply_settings_file(&SettingsFile::parse("screen: 3500 V\n")?)?;
+        assert_eq!(controller.settings.screen.target_value, 32000);
+        Ok(())
+    }
+
+    #[test]
+    fn ramp_keeps_initial_value() {
+        let mut settings = Settings::new();
+        settings.screen.set_ramp(SCREEN_RAMP);
+        assert_eq!(settings.screen.setting.initial_value(), Some(63999));
+        assert_eq!(settings.screen.startup_value(), 63999);
+
+        settings.screen.set_ramp_rate(0.5);
+        assert_eq!(settings.screen.setting.initial_value(), Some(63999));
+
+        // Without an initial value the ramp starts from zero
+        assert_eq!(settings.filament.setting.initial_value(), None);
+        assert_eq!(settings.filament.startup_value(), 0);
+    }
+
+    fn running_controller() -> LEEDController {
+        let mut controller = LEEDController::new();
+        controller.enter_startup_step(StartupStep::Running);
+        controller
+    }
+
+    fn builtin_preset(name: &str) -> Preset {
+        PresetLibrary::builtin()
+            .get(name)
+            .cloned()
+            .expect("Builtin preset exists")
+    }
+
+    // The controller echoing every target at once
+    fn echo_targets(controller: &mut LEEDController) {
+        let settings = &mut controller.settings;
+        for control in [
+            &mut settings.beam_energy,
+            &mut settings.wehnheit,
+            &mut settings.emission,
+            &mut settings.filament,
+            &mut settings.screen,
+            &mut settings.lens1_3.output,
+            &mut settings.lens2.output,
+            &mut settings.suppressor.output,
+        ] {
+            control.read_back(control.target_value);
+        }
+    }
+
+    // Steps through a transition with all targets echoed, returns the steps seen
+    fn run_transition(controller: &mut LEEDController) -> Vec<TransitionStep> {
+        let mut steps = Vec::new();
+        for _ in 0..8 {
+            let Some((_, step)) = controller.transition() else {
+                break;
+            };
+            steps.push(step);
+            echo_targets(controller);
+            controller.update_transition();
+        }
+        steps
+    }
+
+    // Value a ramped control sends one ramp interval after it starts moving
+    fn first_ramp_step(control: &mut ControlValue) -> u32 {
+        let (to_port, port) = mpsc::channel();
+        let (_from_port, replies) = mpsc::channel();
+        let mut link = Transactions::new(to_port, replies, RetryPolicy::default());
+        let ramp = control.ramp().expect("Control ramps");
+
+        control.update(&mut link).expect("Update succeeds");
+        std::thread::sleep(ramp.min_interval);
+        control.update(&mut link).expect("Update succeeds");
+        link.poll().expect("Link is open");
+
+        let frame = port.try_iter().last().expect("A ramp step was sent");
+        Message::from_bytes(&frame).expect("Valid frame").value
+    }
+
+    #[test]
+    fn preset_transition_runs_in_order() -> Result<(), PresetError> {
+        let mut controller = running_controller();
+        controller.settings.screen.set_raw_target(32000);
+        echo_targets(&mut controller);
+
+        let preset = builtin_preset("overview 50 eV");
+        let targets = controller.settings.targets_from_file(&preset.settings)?;
+        controller.select_preset(&preset)?;
+
+        let settings = &controller.settings;
+        assert_eq!(settings.screen.target_value, 0);
+        assert_eq!(settings.filament.target_value, 0);
+        assert_eq!(settings.beam_energy.target_value, 3500);
+
+        echo_targets(&mut controller);
+        controller.update_transition();
+        let settings = &controller.settings;
+        assert_eq!(
+            controller.transition().map(|(_, step)| step),
+            Some(TransitionStep::Filament)
+        );
+        assert_eq!(Some(settings.filament.target_value), targets.filament);
+        assert_eq!(settings.beam_energy.target_value, 3500);
+
+        echo_targets(&mut controller);
+        controller.update_transition();
+        let settings = &controller.settings;
+        assert_eq!(
+            controller.transition().map(|(_, step)| step),
+            Some(TransitionStep::Optics)
+        );
+        assert_eq!(Some(settings.beam_energy.target_value), targets.beam_energy);
+        assert_eq!(settings.screen.current_value, 0);
+
+        echo_targets(&mut controller);
+        controller.update_transition();
+        assert_eq!(
+            controller.transition().map(|(_, step)| step),
+            Some(TransitionStep::RaiseScreen)
+        );
+        assert_eq!(
+            Some(controller.settings.screen.target_value),
+            targets.screen
+        );
+
+        echo_targets(&mut controller);
+        controller.update_transition();
+        assert!(controller.transition().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn preset_without_screen_keeps_it_up() -> Result<(), PresetError> {
+        let mut controller = running_controller();
+        controller.settings.screen.set_raw_target(63999);
+        echo_targets(&mut controller);
+
+        let preset = Preset {
+            name: "beam only".to_string(),
+            settings: SettingsFile::parse("beam: 100 eV\n")?,
+        };
+        controller.select_preset(&preset)?;
+        assert_eq!(controller.settings.screen.target_value, 63999);
+
+        let steps = run_transition(&mut controller);
+        assert_eq!(
+            steps,
+            [
+                TransitionStep::Filament,
+                TransitionStep::Optics,
+                TransitionStep::RaiseScreen
+            ]
+        );
+        assert_eq!(controller.settings.screen.target_value, 63999);
+        Ok(())
+    }
+
+    #[test]
+    fn replaced_preset_raises_screen_to_where_it_was() -> Result<(), PresetError> {
+        let mut controller = running_controller();
+        controller.settings.screen.set_raw_target(32000);
+        echo_targets(&mut controller);
+        controller.select_preset(&builtin_preset("overview 50 eV"))?;
+        echo_targets(&mut controller);
+
+        // Screen is down, the new preset leaves it out
+        let preset = Preset {
+            name: "beam only".to_string(),
+            settings: SettingsFile::parse("beam: 100 eV\n")?,
+        };
+        controller.select_preset(&preset)?;
+        run_transition(&mut controller);
+        assert_eq!(controller.settings.screen.target_value, 63999);
+        assert!(controller.settings.screen.ramp().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn transition_restores_screen_setting() -> Result<(), PresetError> {
+        let mut controller = running_controller();
+        controller.settings.screen.set_raw_target(32000);
+        echo_targets(&mut controller);
+        assert!(controller.settings.screen.ramp().is_none());
+
+        controller.select_preset(&builtin_preset("overview 50 eV"))?;
+        assert_eq!(controller.settings.screen.ramp(), Some(SCREEN_RAMP));
+        run_transition(&mut controller);
+        assert!(controller.transition().is_none());
+        assert!(controller.settings.screen.ramp().is_none());
+
+        // A screen with its own ramp keeps it
+        let ramp = Ramp::new(0.5, Duration::from_millis(100));
+        controller.settings.screen.set_ramp(ramp);
+        controller.select_preset(&builtin_preset("standby"))?;
+        run_transition(&mut controller);
+        assert_eq!(controller.settings.screen.ramp(), Some(ramp));
+        Ok(())
+    }
+
+    #[test]
+    fn preset_transition_ramps_screen_and_filament() -> Result<(), PresetError> {
+        let mut controller = running_controller();
+        controller.settings.screen.set_raw_target(32000);
+        echo_targets(&mut controller);
+        controller.select_preset(&builtin_preset("overview 50 eV"))?;
+
+        let screen = first_ramp_step(&mut controller.settings.screen);
+        assert!(screen > 0 && screen < 32000, "Screen jumped to {}", screen);
+
+        echo_targets(&mut controller);
+        controller.update_transition();
+        let target = controller.settings.filament.target_value as u32;
+        let filament = first_ramp_step(&mut controller.settings.filament);
+        assert!(
+            filament > 0 && filament < target,
+            "Filament jumped to {}",
+            filament
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn graceful_exit_cancels_transition() -> Result<(), PresetError> {
+        let mut controller = running_controller();
+        controller.select_preset(&builtin_preset("overview 50 eV"))?;
+        assert!(controller.transition().is_some());
+
+        controller.graceful_exit();
+        assert!(controller.transition().is_none());
+
+        assert!(matches!(
+            controller.select_preset(&builtin_preset("standby")),
+            Err(PresetError::ShuttingDown)
+        ));
+        assert!(controller.transition().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_names_what_it_waits_for() {
+        let mut controller = running_controller();
+        assert_eq!(controller.shutdown_waiting_for(), None);
+
+        controller.graceful_exit();
+        let waiting = controller.shutdown_waiting_for().unwrap_or_default();
+        assert!(waiting.starts_with("filament setpoint"), "{}", waiting);
+
+        controller.set_connection(ConnectionState::Failed);
+        assert_eq!(
+            controller.shutdown_waiting_for(),
+            Some("link failed".to_string())
+        );
+    }
+
+    #[test]
+    fn builtin_presets_are_in_range() -> Result<(), SettingsFileError> {
+        let settings = Settings::new();
+        for preset in PresetLibrary::builtin().presets() {
+            settings.targets_from_file(&preset.settings)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn preset_rejected_before_startup_completes() {
+        let mut controller = LEEDController::new();
+        assert!(matches!(
+            controller.select_preset(&builtin_preset("overview 50 eV")),
+            Err(PresetError::StartupIncomplete)
+        ));
+        assert!(controller.transition().is_none());
+    }
+
+    #[test]
+    fn stalled_transition_step_aborts() -> Result<(), PresetError> {
+        let mut controller = running_controller();
+        controller.settings.screen.set_raw_target(63999);
+        echo_targets(&mut controller);
+        controller.select_preset(&builtin_preset("overview 50 eV"))?;
+
+        controller.update_transition();
+        assert!(controller.transition().is_some());
+
+        if let Some(transition) = &mut controller.transition {
+            transition.step_timeout = Duration::ZERO;
+        }
+        controller.update_transition();
+        assert!(controller.transition().is_none());
+        // Held where the ramp stalled, no jump once the setting is restored
+        let screen = &controller.settings.screen;
+        assert_eq!(screen.target_value, screen.current_value);
+        assert!(screen.ramp().is_none());
+        Ok(())
+    }
+
+    const BENCH_STEP: Duration = Duration::from_millis(5);
+
+    // Controller and simulator, the test passing frames between them.
+    // Replies go through a filter, which can drop or alter them.
+    struct Bench {
+        controller: LEEDController,
+        simulator: Simulator,
+        link: Transactions,
+        port: mpsc::Receiver<[u8; 6]>,
+        replies: mpsc::Sender<[u8; 6]>,
+        sent: Vec<Message>, // Every frame the controller wrote
+    }
+
+    impl Bench {
+        fn new() -> Self {
+            let (to_port, port) = mpsc::channel();
+            let (replies, from_port) = mpsc::channel();
+            Self {
+                controller: LEEDController::new(),
+                simulator: Simulator::new(),
+                link: Transactions::new(to_port, from_port, RetryPolicy::default()),
+                port,
+                replies,
+                sent: Vec::new(),
+            }
+        }
+
+        fn step(&mut self, filter: &mut dyn FnMut(Message) -> Option<Message>) {
+            self.controller.update(&mut self.link, |_| {});
+            for frame in self.port.try_iter() {
+                self.sent
+                    .push(Message::from_bytes(&frame).expect("Controller sends valid frames"));
+                let reply = self
+                    .simulator
+                    .handle(&frame)
+                    .and_then(|reply| Message::from_bytes(&reply).ok())
+                    .and_then(&mut *filter);
+                if let Some(reply) = reply {
+                    let bytes = reply.to_bytes().expect("Reply is encodable");
+                    self.replies.send(bytes).expect("Link is open");
+                }
+            }
+            thread::sleep(BENCH_STEP);
+            self.simulator.advance(BENCH_STEP);
+        }
+
+        // Steps until the condition holds, false on timeout
+        fn run_until(
+            &mut self,
+            timeout: Duration,
+            filter: &mut dyn FnMut(Message) -> Option<Message>,
+            condition: impl Fn(&LEEDController) -> bool,
+        ) -> bool {
+            let start = Instant::now();
+            while start.elapsed() < timeout {
+                if condition(&self.controller) {
+                    return true;
+                }
+                self.step(filter);
+            }
+            condition(&self.controller)
+        }
+
+        fn start(&mut self) {
+            assert!(
+                self.run_until(Duration::from_secs(5), &mut Some, |controller| {
+                    *controller.startup_step() == StartupStep::Running
+                })
+            );
+        }
+
+        // Runs the shutdown sequence, returning the steps it went through
+        fn shut_down(&mut self) -> Vec<ShutdownStep> {
+            self.controller.graceful_exit();
+            let mut steps = Vec::new();
+            let start = Instant::now();
+            while !self.controller.shutdown_complete() && start.elapsed() < Duration::from_secs(10)
+            {
+                if let Some(step) = self.controller.shutdown_step() {
+                    if steps.last() != Some(&step) {
+                        steps.push(step);
+                    }
+                }
+                self.step(&mut Some);
+            }
+            steps.extend(self.controller.shutdown_step());
+            steps
+        }
+    }
+
+    fn failed(controller: &LEEDController) -> bool {
+        matches!(controller.startup_step(), StartupStep::Failed(_))
+    }
+
+    fn non_zero_setpoints(sent: &[Message]) -> Vec<Message> {
+        sent.iter()
+            .filter(|message| matches!(message.tag, Tag::Control(_)) && message.value != 0)
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn startup_sends_defaults_in_order() {
+        let mut bench = Bench::new();
+        bench.start();
+
+        assert_eq!(
+            bench.sent.first().map(|message| message.tag),
+            Some(Tag::Status)
+        );
+        let setpoints: Vec<Tag> = bench
+            .sent
+            .iter()
+            .map(|message| message.tag)
+            .filter(|tag| matches!(tag, Tag::Control(_) | Tag::DigOut))
+            .collect();
+        assert_eq!(
+            setpoints,
+            [
+                Tag::Control(Control::IFIL_SET1),
+                Tag::Control(Control::WEH_SET),
+                Tag::Control(Control::EMI_SET),
+                Tag::Control(Control::BEAM_SET_INT),
+                Tag::Control(Control::L13_SET),
+                Tag::Control(Control::L2_SET),
+                Tag::Control(Control::RET_SET_INT),
+                Tag::Control(Control::SCR_SET),
+                Tag::DigOut,
+            ]
+        );
+        assert!(bench.controller.started);
+    }
+
+    #[test]
+    fn every_monitor_is_polled_each_interval() {
+        let mut bench = Bench::new();
+        bench.start();
+        let before = bench.sent.len();
+        bench.run_until(Duration::from_millis(1100), &mut Some, |_| false);
+
+        let polled: Vec<Tag> = bench.sent[before..]
+            .iter()
+            .map(|message| message.tag)
+            .collect();
+        for monitor in Monitor::ALL {
+            assert!(polled.contains(&Tag::Monitor(monitor)), "{:?}", monitor);
         }
     }
+
+    #[test]
+    fn startup_fails_on_interlocks() {
+        let mut bench = Bench::new();
+        bench.simulator.set_safety_switch_open(true);
+        assert!(bench.run_until(Duration::from_secs(5), &mut Some, failed));
+        assert!(matches!(
+            bench.controller.startup_step(),
+            StartupStep::Failed(StartupFailure::Interlocks(faults)) if !faults.is_empty()
+        ));
+        assert!(non_zero_setpoints(&bench.sent).is_empty());
+    }
+
+    #[test]
+    fn startup_fails_without_echo() {
+        let mut bench = Bench::new();
+        let mut drop_filament =
+            |reply: Message| (reply.tag != Tag::Control(Control::IFIL_SET1)).then_some(reply);
+        let timeout = STARTUP_STEP_TIMEOUT * 2;
+        assert!(bench.run_until(timeout, &mut drop_filament, failed));
+        assert_eq!(
+            *bench.controller.startup_step(),
+            StartupStep::Failed(StartupFailure::NoEcho("Filament".to_string()))
+        );
+    }
+
+    #[test]
+    fn startup_fails_on_mismatched_echo() {
+        let mut bench = Bench::new();
+        let mut alter_wehnelt = |mut reply: Message| {
+            if reply.tag == Tag::Control(Control::WEH_SET) {
+                reply.value += 1;
+            }
+            Some(reply)
+        };
+        assert!(bench.run_until(Duration::from_secs(5), &mut alter_wehnelt, failed));
+        assert_eq!(
+            *bench.controller.startup_step(),
+            StartupStep::Failed(StartupFailure::Rejected {
+                name: "Wehnheit".to_string(),
+                sent: 0,
+                echoed: 1
+            })
+        );
+    }
+
+    #[test]
+    fn exit_after_failed_startup_sends_no_setpoint() {
+        let mut bench = Bench::new();
+        bench.simulator.set_safety_switch_open(true);
+        assert!(bench.run_until(Duration::from_secs(5), &mut Some, failed));
+
+        bench.shut_down();
+        assert!(bench.controller.shutdown_complete());
+        assert!(
+            non_zero_setpoints(&bench.sent).is_empty(),
+            "{:?}",
+            non_zero_setpoints(&bench.sent)
+        );
+    }
+
+    #[test]
+    fn exit_during_startup_sends_no_new_setpoint() {
+        let mut bench = Bench::new();
+        // Stop halfway, after the emission default has been echoed
+        assert!(
+            bench.run_until(Duration::from_secs(5), &mut Some, |controller| {
+                matches!(controller.startup_step(), StartupStep::SendDefault(index) if *index >= 3)
+            })
+        );
+        let before = bench.sent.len();
+
+        bench.shut_down();
+        assert!(bench.controller.shutdown_complete());
+        let after = non_zero_setpoints(&bench.sent[before..]);
+        assert!(after.is_empty(), "{:?}", after);
+    }
+
+    #[test]
+    fn shutdown_runs_against_simulator() -> Result<(), TargetError> {
+        let mut bench = Bench::new();
+        bench.start();
+
+        // Small values, so the ramps and the filament decay finish quickly
+        bench
+            .controller
+            .set_target(settings_file::FILAMENT, 0.02, "A")?;
+        bench
+            .controller
+            .set_target(settings_file::SCREEN, 0.3, "kV")?;
+        assert!(
+            bench.run_until(Duration::from_secs(5), &mut Some, |controller| {
+                controller.settings.filament.at_target() && controller.settings.screen.at_target()
+            })
+        );
+
+        let steps = bench.shut_down();
+        assert_eq!(
+            steps,
+            [
+                ShutdownStep::RampFilamentDown,
+                ShutdownStep::WaitFilamentOff,
+                ShutdownStep::RampHighVoltageDown,
+                ShutdownStep::SendEmiMax,
+                ShutdownStep::Complete,
+            ]
+        );
+
+        assert_eq!(bench.simulator.dac(Control::IFIL_SET1), 0);
+        assert_eq!(bench.simulator.dac(Control::SCR_SET), 0);
+        assert_eq!(bench.simulator.dac(Control::BEAM_SET_INT), 0);
+        assert_eq!(
+            bench.sent.last().map(|message| message.tag),
+            Some(Tag::Control(Control::EMI_MAX))
+        );
+        Ok(())
+    }
 }
@@ -1,4 +1,16 @@
 pub mod protocol;
 pub mod sniffer;
+pub mod capture;
+pub mod pcapng;
+pub mod bridge;
+pub mod transaction;
+pub mod keepalive;
+pub mod transport;
+pub mod simulator;
+pub mod discovery;
+pub mod setting;
+pub mod preset;
+pub mod settings_file;
 pub mod leed_controller;
 pub mod tui_log;
+pub mod args;
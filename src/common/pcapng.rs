@@ -0,0 +1,285 @@
+use super::capture::{CaptureReader, Direction, Record};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Writes frames as pcapng, for Wireshark.
+// Every frame is one packet of 6 bytes on the user link type DLT_USER0,
+// with the direction in the epb_flags option, seen from the host:
+// inbound = controller to host, outbound = host to controller.
+// docs/leed_dissector.lua decodes the frames.
+
+pub const LINKTYPE_USER0: u16 = 147;
+
+const SHB_TYPE: u32 = 0x0A0D0D0A;
+const IDB_TYPE: u32 = 0x00000001;
+const EPB_TYPE: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const FLAGS_INBOUND: u32 = 0b01;
+const FLAGS_OUTBOUND: u32 = 0b10;
+
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+    base: SystemTime, // Time of the start of the capture
+}
+
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(mut writer: W, base: SystemTime) -> io::Result<Self> {
+        write_block(&mut writer, SHB_TYPE, &section_header())?;
+        write_block(&mut writer, IDB_TYPE, &interface_description())?;
+        Ok(Self { writer, base })
+    }
+
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        let time = self.base + record.time;
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_micros() as u64;
+
+        let flags = match record.direction {
+            Direction::ControllerToHost => FLAGS_INBOUND,
+            Direction::HostToController => FLAGS_OUTBOUND,
+        };
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&0u32.to_le_bytes()); // Interface id
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(record.frame.len() as u32).to_le_bytes()); // Captured
+        body.extend_from_slice(&(record.frame.len() as u32).to_le_bytes()); // Original
+        body.extend_from_slice(&record.frame);
+        pad(&mut body);
+        push_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut body, OPT_END, &[]);
+
+        write_block(&mut self.writer, EPB_TYPE, &body)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exported {
+    pub frames: usize,
+    // The capture had no start time, the times assume it ended
+    // when its file was last modified
+    pub start_estimated: bool,
+}
+
+// Converts a capture file. Times are the start time from the capture header
+// plus the record times. Version 1 captures lack the start time, it is taken
+// as the file modification time minus the capture duration instead.
+pub fn export<P: AsRef<Path>, Q: AsRef<Path>>(capture: P, pcapng: Q) -> io::Result<Exported> {
+    let reader = CaptureReader::open(&capture)?;
+    let start_time = reader.start_time();
+    let records = reader.collect::<io::Result<Vec<Record>>>()?;
+
+    let base = match start_time {
+        Some(start_time) => start_time,
+        None => {
+            let modified = std::fs::metadata(&capture)?.modified()?;
+            let duration = records.last().map(|last| last.time).unwrap_or_default();
+            modified.checked_sub(duration).unwrap_or(modified)
+        }
+    };
+
+    let mut writer = PcapngWriter::new(BufWriter::new(File::create(pcapng)?), base)?;
+    for record in &records {
+        writer.write_record(record)?;
+    }
+    writer.flush()?;
+
+    Ok(Exported {
+        frames: records.len(),
+        start_estimated: start_time.is_none(),
+    })
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes()); // Major version
+    body.extend_from_slice(&0u16.to_le_bytes()); // Minor version
+    body.extend_from_slice(&(-1i64).to_le_bytes()); // Section length unknown
+    push_option(&mut body, OPT_COMMENT, b"NK LEED RS232 frames");
+    push_option(&mut body, OPT_END, &[]);
+    body
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes()); // Reserved
+    body.extend_from_slice(&0u32.to_le_bytes()); // No snap length
+    body
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+// Blocks and option values are padded to 32 bits
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let total_len = (body.len() + 12) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::capture::Recorder;
+    use std::process;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(word)
+    }
+
+    // Splits into (type, body), checking both length fields and the alignment
+    fn blocks(mut bytes: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !bytes.is_empty() {
+            let block_type = u32_at(bytes, 0);
+            let total_len = u32_at(bytes, 4) as usize;
+            assert_eq!(total_len % 4, 0, "Block {:#x} not padded", block_type);
+            assert_eq!(u32_at(bytes, total_len - 4) as usize, total_len);
+            blocks.push((block_type, bytes[8..total_len - 4].to_vec()));
+            bytes = &bytes[total_len..];
+        }
+        blocks
+    }
+
+    const FRAME: [u8; 6] = [0x02, 0x34, 0x12, 0x34, 0x34, 0x03];
+
+    fn written(records: &[Record], base: SystemTime) -> io::Result<Vec<u8>> {
+        let mut writer = PcapngWriter::new(Vec::new(), base)?;
+        for record in records {
+            writer.write_record(record)?;
+        }
+        Ok(writer.writer)
+    }
+
+    #[test]
+    fn writes_section_and_interface() -> io::Result<()> {
+        let bytes = written(&[], UNIX_EPOCH)?;
+        let blocks = blocks(&bytes);
+        assert_eq!(blocks.len(), 2);
+
+        let (block_type, shb) = &blocks[0];
+        assert_eq!(*block_type, SHB_TYPE);
+        assert_eq!(u32_at(shb, 0), BYTE_ORDER_MAGIC);
+        assert_eq!((u16_at(shb, 4), u16_at(shb, 6)), (1, 0));
+        assert_eq!(&shb[8..16], &[0xFF; 8]);
+        let comment = b"NK LEED RS232 frames";
+        assert_eq!(u16_at(shb, 16), OPT_COMMENT);
+        assert_eq!(u16_at(shb, 18) as usize, comment.len());
+        assert_eq!(&shb[20..20 + comment.len()], comment);
+        assert_eq!(&shb[40..], &[0; 4]); // End of options
+
+        let (block_type, idb) = &blocks[1];
+        assert_eq!(*block_type, IDB_TYPE);
+        assert_eq!(idb.len(), 8);
+        assert_eq!(u16_at(idb, 0), LINKTYPE_USER0);
+        assert_eq!(LINKTYPE_USER0, 147); // DLT_USER0
+        Ok(())
+    }
+
+    #[test]
+    fn writes_frame_as_enhanced_packet() -> io::Result<()> {
+        let base = UNIX_EPOCH + Duration::from_secs(0x1_0000_0000);
+        let records = [
+            Record {
+                time: Duration::from_micros(1500),
+                direction: Direction::HostToController,
+                frame: FRAME,
+            },
+            Record {
+                time: Duration::from_micros(2500),
+                direction: Direction::ControllerToHost,
+                frame: FRAME,
+            },
+        ];
+        let bytes = written(&records, base)?;
+        let blocks = blocks(&bytes);
+        assert_eq!(blocks.len(), 4);
+
+        let (block_type, epb) = &blocks[2];
+        assert_eq!(*block_type, EPB_TYPE);
+        let micros = 0x1_0000_0000 * 1_000_000 + 1500u64;
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&0u32.to_le_bytes()); // Interface
+        expected.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        expected.extend_from_slice(&(micros as u32).to_le_bytes());
+        expected.extend_from_slice(&6u32.to_le_bytes());
+        expected.extend_from_slice(&6u32.to_le_bytes());
+        expected.extend_from_slice(&FRAME);
+        expected.extend_from_slice(&[0, 0]); // Padding
+        expected.extend_from_slice(&[2, 0, 4, 0]); // epb_flags
+        expected.extend_from_slice(&FLAGS_OUTBOUND.to_le_bytes());
+        expected.extend_from_slice(&[0; 4]); // End of options
+        assert_eq!(epb, &expected);
+        assert_eq!(epb.len() + 12, 52); // Block length
+
+        let (_, inbound) = &blocks[3];
+        assert_eq!(u32_at(inbound, 32), FLAGS_INBOUND);
+        Ok(())
+    }
+
+    #[test]
+    fn export_uses_capture_start_time() -> io::Result<()> {
+        let dir = std::env::temp_dir();
+        let capture = dir.join(format!("export-{}.leedcap", process::id()));
+        let pcapng = dir.join(format!("export-{}.pcapng", process::id()));
+
+        let mut recorder = Recorder::create(&capture)?;
+        recorder.record(Direction::HostToController, &FRAME)?;
+        drop(recorder);
+        let start = CaptureReader::open(&capture)?.start_time();
+
+        let exported = export(&capture, &pcapng);
+        let bytes = std::fs::read(&pcapng);
+        let _ = std::fs::remove_file(&capture);
+        let _ = std::fs::remove_file(&pcapng);
+
+        assert_eq!(
+            exported?,
+            Exported {
+                frames: 1,
+                start_estimated: false
+            }
+        );
+        let bytes = bytes?;
+        let (_, epb) = &blocks(&bytes)[2];
+        let micros = ((u32_at(epb, 4) as u64) << 32) + u32_at(epb, 8) as u64;
+        let start = start
+            .and_then(|start| start.duration_since(UNIX_EPOCH).ok())
+            .map(|start| start.as_micros() as u64);
+        let after_start = start.and_then(|start| micros.checked_sub(start));
+        assert!(after_start.is_some_and(|after| after < 1_000_000));
+        Ok(())
+    }
+}
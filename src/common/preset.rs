@@ -0,0 +1,179 @@
+use super::settings_file::{SettingsFile, SettingsFileError};
+use log::info;
+use std::fs;
+use std::path::Path;
+
+// Named operating points, in the settings file format.
+// Keys a preset leaves out keep their current target when it is selected.
+
+const BUILTIN: [(&str, &str); 3] = [
+    (
+        "standby",
+        "beam: 0 eV
+filament: 0 A
+screen: 0 kV
+",
+    ),
+    (
+        "overview 50 eV",
+        "beam: 50 eV
+Wehn: 0 V
+lens2 off: 0 V
+lens2 gain: 0.5
+lens1 off: 11.8 V
+lens1 gain: 2.0
+filament: 1.835 A
+emission: 50 uA
+screen: 7 kV
+suppressor: 80%
+",
+    ),
+    (
+        "alignment 500 eV",
+        "beam: 500 eV
+Wehn: 0 V
+lens2 off: 0 V
+lens2 gain: 0.5
+lens1 off: 11.8 V
+lens1 gain: 2.0
+filament: 1.835 A
+emission: 50 uA
+screen: 7 kV
+suppressor: 80%
+",
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    pub settings: SettingsFile,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PresetLibrary {
+    presets: Vec<Preset>,
+}
+
+impl PresetLibrary {
+    pub fn builtin() -> Self {
+        let presets = BUILTIN
+            .iter()
+            .map(|(name, text)| Preset {
+                name: name.to_string(),
+                settings: SettingsFile::parse(text).expect("Builtin preset is valid"),
+            })
+            .collect();
+        Self { presets }
+    }
+
+    // Builtin presets, plus one per file in the directory, named after the file.
+    // A file named like a builtin preset replaces it.
+    pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Self, SettingsFileError> {
+        let mut library = Self::builtin();
+
+        let mut paths = fs::read_dir(&dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+
+        for path in paths.iter().filter(|path| path.is_file()) {
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            library.insert(Preset {
+                name: name.to_string(),
+                settings: SettingsFile::load(path)?,
+            });
+        }
+
+        info!(
+            "Loaded {} presets from {}",
+            library.presets.len(),
+            dir.as_ref().display()
+        );
+        Ok(library)
+    }
+
+    // Replaces a preset of the same name, or appends
+    pub fn insert(&mut self, preset: Preset) {
+        match self.presets.iter_mut().find(|p| p.name == preset.name) {
+            Some(existing) => *existing = preset,
+            None => self.presets.push(preset),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+
+    pub fn presets(&self) -> &[Preset] {
+        &self.presets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::path::PathBuf;
+    use std::process;
+
+    // Empty directory for one test, removed again on drop
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> io::Result<Self> {
+            let path = std::env::temp_dir().join(format!("presets-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path)?;
+            Ok(Self(path))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn builtin_presets_parse() {
+        let library = PresetLibrary::builtin();
+        assert_eq!(library.presets().len(), BUILTIN.len());
+        assert!(library.get("standby").is_some());
+    }
+
+    #[test]
+    fn load_dir_adds_and_replaces_presets() -> Result<(), SettingsFileError> {
+        let dir = TestDir::new("load")?;
+        fs::write(dir.0.join("low beam"), "beam: 20 eV\n")?;
+        fs::write(dir.0.join("standby.txt"), "filament: 0 A\n")?;
+        fs::create_dir(dir.0.join("subdir"))?;
+
+        let library = PresetLibrary::load_dir(&dir.0)?;
+        assert_eq!(library.presets().len(), BUILTIN.len() + 1);
+        let low_beam = library.get("low beam").map(|preset| &preset.settings);
+        assert_eq!(low_beam, Some(&SettingsFile::parse("beam: 20 eV\n")?));
+        let standby = library.get("standby").map(|preset| &preset.settings);
+        assert_eq!(standby, Some(&SettingsFile::parse("filament: 0 A\n")?));
+        Ok(())
+    }
+
+    #[test]
+    fn load_dir_rejects_invalid_files() -> io::Result<()> {
+        let dir = TestDir::new("invalid")?;
+        fs::write(dir.0.join("broken"), "beam 20 eV\n")?;
+        assert!(matches!(
+            PresetLibrary::load_dir(&dir.0),
+            Err(SettingsFileError::Syntax { line: 1, .. })
+        ));
+
+        let missing = dir.0.join("missing");
+        assert!(matches!(
+            PresetLibrary::load_dir(missing),
+            Err(SettingsFileError::Io(_))
+        ));
+        Ok(())
+    }
+}
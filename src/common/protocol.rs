@@ -1,22 +1,88 @@
-use log::{error, warn};
+use std::fmt::Display;
 
 #[derive(Debug, Clone, Copy)]
+pub enum ProtocolError {
+    BadChecksum { expected: u8, actual: u8 },
+    BadFraming,
+    UnknownId(u8),
+    UnencodableTag(Tag),
+    ValueOutOfRange(u32),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::BadChecksum { expected, actual } => write!(
+                formatter,
+                "Bad checksum, expected {:02X} got {:02X}",
+                expected, actual
+            ),
+            ProtocolError::BadFraming => write!(formatter, "Bad framing"),
+            ProtocolError::UnknownId(id) => {
+                write!(formatter, "Unknown id outside $20..$7F: {:02X}", id)
+            }
+            ProtocolError::UnencodableTag(tag) => write!(formatter, "Unencodable tag: {:?}", tag),
+            ProtocolError::ValueOutOfRange(value) => {
+                write!(formatter, "Value out of 16-bit range: {}", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+// Number of protocol errors in received frames, per error class.
+// Unencodable tags and out of range values only happen when encoding a
+// message to send, they are reported where it is sent and not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ErrorCounts {
+    pub bad_checksum: u32,
+    pub bad_framing: u32,
+    pub unknown_id: u32,
+}
+
+impl ErrorCounts {
+    pub fn record(&mut self, error: &ProtocolError) {
+        let count = match error {
+            ProtocolError::BadChecksum { .. } => &mut self.bad_checksum,
+            ProtocolError::BadFraming => &mut self.bad_framing,
+            ProtocolError::UnknownId(_) => &mut self.unknown_id,
+            ProtocolError::UnencodableTag(_) | ProtocolError::ValueOutOfRange(_) => return,
+        };
+        *count += 1;
+    }
+
+    pub fn total(&self) -> u32 {
+        self.bad_checksum + self.bad_framing + self.unknown_id
+    }
+}
+
+impl Display for ErrorCounts {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "checksum: {}, framing: {}, unknown id: {}",
+            self.bad_checksum, self.bad_framing, self.unknown_id
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Tag {
-    ADC1,
-    ADC2,
-    ADC3,
+    Monitor(Monitor),
     Control(Control),
-    // Status(Status),
-    DigOut, // DAC(u8)
+    Status,
+    DigOut,
+    Unknown(u8), // Valid id without a known meaning, passed through as is
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Message {
     pub tag: Tag,
     pub value: u32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum Control {
     L2_SET,
@@ -30,72 +96,253 @@ pub enum Control {
     EMI_MAX,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum Monitor {
+    L13_MON,
+    EMI_MON,
+    L2_MON,
+    BEAM_MON,
+    I0_MON,
+    RET_MON,
+    SCR_MON,
+    IFIL_MON,
+    WEH_MON,
+}
+
+impl Monitor {
+    // In ADC id order, $41..$49
+    pub const ALL: [Monitor; 9] = [
+        Monitor::L13_MON,
+        Monitor::EMI_MON,
+        Monitor::L2_MON,
+        Monitor::BEAM_MON,
+        Monitor::I0_MON,
+        Monitor::RET_MON,
+        Monitor::SCR_MON,
+        Monitor::IFIL_MON,
+        Monitor::WEH_MON,
+    ];
+}
+
 impl Message {
-    fn from_raw(m: RawMessage) -> Option<Message> {
+    fn from_raw(m: RawMessage) -> Result<Message, ProtocolError> {
         let tag = match m.id {
-            0x21 => Some(Tag::DigOut),
-
-            0x31 => Some(Tag::Control(Control::L2_SET)),
-            0x32 => Some(Tag::Control(Control::WEH_SET)),
-            0x33 => Some(Tag::Control(Control::L13_SET)),
-            0x34 => Some(Tag::Control(Control::SCR_SET)),
-            0x35 => Some(Tag::Control(Control::RET_SET_INT)),
-            0x36 => Some(Tag::Control(Control::BEAM_SET_INT)),
-            0x37 => Some(Tag::Control(Control::IFIL_SET1)),
-            0x38 => Some(Tag::Control(Control::EMI_SET)),
-            0x39 => Some(Tag::Control(Control::EMI_MAX)),
-
-            0x42 => Some(Tag::ADC1),
-            0x45 => Some(Tag::ADC2),
-            0x48 => Some(Tag::ADC3),
-
-            _ => {
-                warn!("Unhandled message: {:?}", m);
-                None
-            }
-        }?;
+            0x20 => Tag::Status,
+            0x21 => Tag::DigOut,
+
+            0x31 => Tag::Control(Control::L2_SET),
+            0x32 => Tag::Control(Control::WEH_SET),
+            0x33 => Tag::Control(Control::L13_SET),
+            0x34 => Tag::Control(Control::SCR_SET),
+            0x35 => Tag::Control(Control::RET_SET_INT),
+            0x36 => Tag::Control(Control::BEAM_SET_INT),
+            0x37 => Tag::Control(Control::IFIL_SET1),
+            0x38 => Tag::Control(Control::EMI_SET),
+            0x39 => Tag::Control(Control::EMI_MAX),
+
+            0x41 => Tag::Monitor(Monitor::L13_MON),
+            0x42 => Tag::Monitor(Monitor::EMI_MON),
+            0x43 => Tag::Monitor(Monitor::L2_MON),
+            0x44 => Tag::Monitor(Monitor::BEAM_MON),
+            0x45 => Tag::Monitor(Monitor::I0_MON),
+            0x46 => Tag::Monitor(Monitor::RET_MON),
+            0x47 => Tag::Monitor(Monitor::SCR_MON),
+            0x48 => Tag::Monitor(Monitor::IFIL_MON),
+            0x49 => Tag::Monitor(Monitor::WEH_MON),
 
-        Some(Message {
+            id if VALID_IDS.contains(&id) => Tag::Unknown(id),
+            id => return Err(ProtocolError::UnknownId(id)),
+        };
+
+        Ok(Message {
             tag,
             value: ((m.msb as u32) << 8) + (m.lsb as u32),
         })
     }
 
-    fn to_raw(self) -> Option<RawMessage> {
+    fn to_raw(self) -> Result<RawMessage, ProtocolError> {
+        if self.value > 0xFFFF {
+            return Err(ProtocolError::ValueOutOfRange(self.value));
+        }
+
         let id = match self.tag {
-            Tag::Control(Control::L2_SET) => Some(0x31),
-            Tag::Control(Control::WEH_SET) => Some(0x32),
-            Tag::Control(Control::L13_SET) => Some(0x33),
-            Tag::Control(Control::SCR_SET) => Some(0x34),
-            Tag::Control(Control::RET_SET_INT) => Some(0x35),
-            Tag::Control(Control::BEAM_SET_INT) => Some(0x36),
-            Tag::Control(Control::IFIL_SET1) => Some(0x37),
-            Tag::Control(Control::EMI_SET) => Some(0x38),
-            Tag::Control(Control::EMI_MAX) => Some(0x39),
-            Tag::ADC1 => Some(0x42),
-            Tag::ADC2 => Some(0x45),
-            Tag::ADC3 => Some(0x48),
-            msg => {
-                error!("Unimplemented: {:?}", msg);
-                None
-            }
-        }?;
+            Tag::Status => 0x20,
+            Tag::DigOut => 0x21,
+            Tag::Control(Control::L2_SET) => 0x31,
+            Tag::Control(Control::WEH_SET) => 0x32,
+            Tag::Control(Control::L13_SET) => 0x33,
+            Tag::Control(Control::SCR_SET) => 0x34,
+            Tag::Control(Control::RET_SET_INT) => 0x35,
+            Tag::Control(Control::BEAM_SET_INT) => 0x36,
+            Tag::Control(Control::IFIL_SET1) => 0x37,
+            Tag::Control(Control::EMI_SET) => 0x38,
+            Tag::Control(Control::EMI_MAX) => 0x39,
+            Tag::Monitor(Monitor::L13_MON) => 0x41,
+            Tag::Monitor(Monitor::EMI_MON) => 0x42,
+            Tag::Monitor(Monitor::L2_MON) => 0x43,
+            Tag::Monitor(Monitor::BEAM_MON) => 0x44,
+            Tag::Monitor(Monitor::I0_MON) => 0x45,
+            Tag::Monitor(Monitor::RET_MON) => 0x46,
+            Tag::Monitor(Monitor::SCR_MON) => 0x47,
+            Tag::Monitor(Monitor::IFIL_MON) => 0x48,
+            Tag::Monitor(Monitor::WEH_MON) => 0x49,
+            Tag::Unknown(id) if VALID_IDS.contains(&id) => id,
+            tag @ Tag::Unknown(_) => return Err(ProtocolError::UnencodableTag(tag)),
+        };
 
-        Some(RawMessage {
+        Ok(RawMessage {
             id,
             msb: (self.value >> 8) as u8,
             lsb: (self.value & 0xFF) as u8,
         })
     }
 
-    pub fn from_bytes(bytes: &[u8; 6]) -> Option<Message> {
+    // Decoded status byte, if this is a reply to a status request
+    pub fn status(&self) -> Option<StatusBits> {
+        match self.tag {
+            Tag::Status => Some(StatusBits(self.value as u8)),
+            _ => None,
+        }
+    }
+
+    // Decoded digital output bits, if this is a digital output message
+    pub fn dig_out(&self) -> Option<DigOutBits> {
+        match self.tag {
+            Tag::DigOut => Some(DigOutBits(self.value as u8)),
+            _ => None,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8; 6]) -> Result<Message, ProtocolError> {
         let raw = RawMessage::parse(bytes)?;
         Message::from_raw(raw)
     }
 
-    pub fn to_bytes(&self) -> Option<[u8; 6]> {
+    pub fn to_bytes(&self) -> Result<[u8; 6], ProtocolError> {
         let raw = self.to_raw()?;
-        Some(raw.to_bytes())
+        Ok(raw.to_bytes())
+    }
+}
+
+// Slave status byte, as replied to ID $20
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusBits(pub u8);
+
+impl StatusBits {
+    pub const NOT_MONITOR: u8 = 0x01;
+    pub const SHUTDOWN: u8 = 0x02;
+    pub const ENABLE: u8 = 0x04;
+    pub const OK_15V: u8 = 0x08;
+    pub const OK_15VHV: u8 = 0x10;
+    pub const SAFETY_SWITCH_OPEN: u8 = 0x20;
+
+    fn is_set(&self, bit: u8) -> bool {
+        self.0 & bit != 0
+    }
+
+    pub fn monitor_mode(&self) -> bool {
+        !self.is_set(Self::NOT_MONITOR)
+    }
+
+    pub fn shutdown(&self) -> bool {
+        self.is_set(Self::SHUTDOWN)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.is_set(Self::ENABLE)
+    }
+
+    pub fn supply_15v_ok(&self) -> bool {
+        self.is_set(Self::OK_15V)
+    }
+
+    pub fn supply_15v_hv_ok(&self) -> bool {
+        self.is_set(Self::OK_15VHV)
+    }
+
+    pub fn safety_switch_open(&self) -> bool {
+        self.is_set(Self::SAFETY_SWITCH_OPEN)
+    }
+
+    // Human readable list of conditions preventing normal operation
+    pub fn faults(&self) -> Vec<&'static str> {
+        let mut faults = Vec::new();
+        if self.safety_switch_open() {
+            faults.push("Safety switch open");
+        }
+        if !self.supply_15v_ok() {
+            faults.push("15V not OK");
+        }
+        if !self.supply_15v_hv_ok() {
+            faults.push("15V HV not OK");
+        }
+        if self.shutdown() {
+            faults.push("Shutdown");
+        }
+        if !self.enabled() {
+            faults.push("Not enabled");
+        }
+        if self.monitor_mode() {
+            faults.push("Monitor mode");
+        }
+        faults
+    }
+}
+
+// Digital output bits, set with ID $21.
+// Cleared to extern by the controller if the link goes silent.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DigOutBits(pub u8);
+
+impl DigOutBits {
+    pub const LEED_AUGER: u8 = 0x40;
+    pub const BEAM_INT_EXT: u8 = 0x80;
+
+    fn with(self, bit: u8, set: bool) -> Self {
+        if set {
+            Self(self.0 | bit)
+        } else {
+            Self(self.0 & !bit)
+        }
+    }
+
+    // "1" = intern, "0" = extern
+    pub fn leed_internal(&self) -> bool {
+        self.0 & Self::LEED_AUGER != 0
+    }
+
+    pub fn beam_internal(&self) -> bool {
+        self.0 & Self::BEAM_INT_EXT != 0
+    }
+
+    pub fn with_leed_internal(self, internal: bool) -> Self {
+        self.with(Self::LEED_AUGER, internal)
+    }
+
+    pub fn with_beam_internal(self, internal: bool) -> Self {
+        self.with(Self::BEAM_INT_EXT, internal)
+    }
+}
+
+impl From<DigOutBits> for Message {
+    fn from(bits: DigOutBits) -> Self {
+        Message {
+            tag: Tag::DigOut,
+            value: bits.0 as u32,
+        }
+    }
+}
+
+impl Display for DigOutBits {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let side = |internal| if internal { "intern" } else { "extern" };
+        write!(
+            formatter,
+            "LEED/AUGER: {}, BEAM: {}",
+            side(self.leed_internal()),
+            side(self.beam_internal())
+        )
     }
 }
 
@@ -108,66 +355,109 @@ pub struct RawMessage {
 
 impl RawMessage {
     pub fn checksum(&self) -> u8 {
-        0x2 ^ self.id ^ self.msb ^ self.lsb
+        STX ^ self.id ^ self.msb ^ self.lsb
     }
 
-    fn parse(bytes: &[u8; 6]) -> Option<RawMessage> {
+    pub fn parse(bytes: &[u8; 6]) -> Result<RawMessage, ProtocolError> {
         match *bytes {
-            [0x2, id, msb, lsb, bcc, 0x3] => {
+            [STX, id, msb, lsb, bcc, ETX] => {
                 let raw_msg = RawMessage { id, msb, lsb };
                 let check = raw_msg.checksum();
                 if check == bcc {
-                    Some(raw_msg)
+                    Ok(raw_msg)
                 } else {
-                    error!(
-                        "Invalid checksum for message: {:02X?}. {}:{}",
-                        bytes, check, bcc
-                    );
-                    None
+                    Err(ProtocolError::BadChecksum {
+                        expected: check,
+                        actual: bcc,
+                    })
                 }
             }
-            _ => {
-                error!("Received unparseable message");
-                None
-            }
+            _ => Err(ProtocolError::BadFraming),
         }
     }
 
     pub fn to_bytes(&self) -> [u8; 6] {
         let bcc = self.checksum();
-        [0x2, self.id, self.msb, self.lsb, bcc, 0x3]
+        [STX, self.id, self.msb, self.lsb, bcc, ETX]
+    }
+
+    // Same check as parse, without logging
+    fn is_valid_frame(bytes: &[u8]) -> bool {
+        match *bytes {
+            [STX, id, msb, lsb, bcc, ETX] => RawMessage { id, msb, lsb }.checksum() == bcc,
+            _ => false,
+        }
     }
 }
 
-// enum ADC {
-//     A1,
-//     A2,
-//     A3,
-//     A4,
-//     A5,
-//     A6,
-//     A7,
-//     A8,
-//     A9,
-// }
+const STX: u8 = 0x2;
+const VALID_IDS: std::ops::RangeInclusive<u8> = 0x20..=0x7F;
+const ETX: u8 = 0x3;
+pub const FRAME_LEN: usize = 6;
 
-// impl ADC {
-//     fn parse(byte: u8) -> Option<ADC> {
-//         match byte {
-//             0x41 => Some(ADC::A1),
-//             0x42 => Some(ADC::A2),
-//             0x43 => Some(ADC::A3),
-//             0x44 => Some(ADC::A4),
-//             0x45 => Some(ADC::A5),
-//             0x46 => Some(ADC::A6),
-//             0x47 => Some(ADC::A7),
-//             0x48 => Some(ADC::A8),
-//             0x49 => Some(ADC::A9),
+// Splits a byte stream into frames.
+// Bytes which are not part of a valid STX..ETX frame are dropped until
+// the stream lines up again, so a lost byte only costs the frames around it.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    discarded: usize,
+}
 
-//             _ => None,
-//         }
-//     }
-// }
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::with_capacity(64),
+            discarded: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn next_frame(&mut self) -> Option<[u8; FRAME_LEN]> {
+        loop {
+            let skip = self
+                .buffer
+                .iter()
+                .position(|b| *b == STX)
+                .unwrap_or(self.buffer.len());
+            self.discard(skip);
+
+            if self.buffer.len() < FRAME_LEN {
+                return None;
+            }
+
+            if RawMessage::is_valid_frame(&self.buffer[..FRAME_LEN]) {
+                let mut frame = [0; FRAME_LEN];
+                frame.copy_from_slice(&self.buffer[..FRAME_LEN]);
+                self.buffer.drain(..FRAME_LEN);
+                return Some(frame);
+            }
+
+            // Not a frame start after all, hunt for the next STX
+            self.discard(1);
+        }
+    }
+
+    // Pushes a chunk and returns all frames completed by it
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<[u8; FRAME_LEN]> {
+        self.push(bytes);
+        std::iter::from_fn(|| self.next_frame()).collect()
+    }
+
+    // Total number of bytes dropped while resynchronising
+    pub fn discarded(&self) -> usize {
+        self.discarded
+    }
+
+    fn discard(&mut self, count: usize) {
+        if count > 0 {
+            self.buffer.drain(..count);
+            self.discarded += count;
+        }
+    }
+}
 
 // #[derive(Debug)]
 // enum DAC {
@@ -200,20 +490,229 @@ impl RawMessage {
 //     }
 // }
 
-// #[derive(Debug)]
-// #[allow(non_camel_case_types)]
-// enum DigOutBits {
-//     LEED_AUGER,
-//     BEAM_INT_EXT,
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-// #[derive(Debug)]
-// #[allow(non_camel_case_types)]
-// enum Status {
-//     MON,
-//     SHUTDOWN,
-//     ENABLE,
-//     OK_15V,
-//     OK_15VHV,
-//     SAFETY_SWITCH,
-// }
+    fn frame(tag: Tag, value: u32) -> [u8; FRAME_LEN] {
+        Message { tag, value }
+            .to_bytes()
+            .expect("Message is encodable")
+    }
+
+    #[test]
+    fn monitor_ids_round_trip() -> Result<(), ProtocolError> {
+        for (id, monitor) in (0x41..=0x49).zip(Monitor::ALL) {
+            let raw = RawMessage {
+                id,
+                msb: 0x12,
+                lsb: 0x34,
+            };
+            let message = Message::from_raw(raw)?;
+            assert_eq!(message.tag, Tag::Monitor(monitor));
+            assert_eq!(message.value, 0x1234);
+            assert_eq!(message.to_raw()?.id, id);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_ids_round_trip() -> Result<(), ProtocolError> {
+        for id in [0x22, 0x3A, 0x50, 0x7F] {
+            let bytes = RawMessage {
+                id,
+                msb: 0xAB,
+                lsb: 0xCD,
+            }
+            .to_bytes();
+            let message = Message::from_bytes(&bytes)?;
+            assert_eq!(message.tag, Tag::Unknown(id));
+            assert_eq!(message.value, 0xABCD);
+            assert_eq!(message.to_bytes()?, bytes);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn status_bits_decode() -> Result<(), ProtocolError> {
+        let status = |value| {
+            Message::from_bytes(&frame(Tag::Status, value))?
+                .status()
+                .ok_or(ProtocolError::BadFraming)
+        };
+
+        // /MON set, enabled, both supplies OK, switch closed
+        let normal = status(0x1D)?;
+        assert!(!normal.monitor_mode());
+        assert!(!normal.shutdown());
+        assert!(normal.enabled());
+        assert!(normal.supply_15v_ok());
+        assert!(normal.supply_15v_hv_ok());
+        assert!(!normal.safety_switch_open());
+        assert!(normal.faults().is_empty());
+
+        type IsSet = fn(&StatusBits) -> bool;
+        let bits: [(u32, IsSet); 6] = [
+            (0x01, |bits| !bits.monitor_mode()),
+            (0x02, StatusBits::shutdown),
+            (0x04, StatusBits::enabled),
+            (0x08, StatusBits::supply_15v_ok),
+            (0x10, StatusBits::supply_15v_hv_ok),
+            (0x20, StatusBits::safety_switch_open),
+        ];
+        for (bit, is_set) in bits {
+            assert!(is_set(&status(bit)?), "{:#04x} set", bit);
+            assert!(!is_set(&status(0x3F & !bit)?), "{:#04x} clear", bit);
+        }
+
+        assert_eq!(
+            status(0x00)?.faults(),
+            ["15V not OK", "15V HV not OK", "Not enabled", "Monitor mode"]
+        );
+        assert_eq!(status(0x3F)?.faults(), ["Safety switch open", "Shutdown"]);
+        assert!(Message::from_bytes(&frame(Tag::DigOut, 0x1D))?
+            .status()
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn dig_out_bits_round_trip() -> Result<(), ProtocolError> {
+        let cases = [
+            (false, false, 0x00),
+            (true, false, 0x40),
+            (false, true, 0x80),
+            (true, true, 0xC0),
+        ];
+        for (leed, beam, value) in cases {
+            let bits = DigOutBits::default()
+                .with_leed_internal(leed)
+                .with_beam_internal(beam);
+            let bytes = Message::from(bits).to_bytes()?;
+            assert_eq!(bytes, frame(Tag::DigOut, value));
+
+            let decoded = Message::from_bytes(&bytes)?.dig_out();
+            assert_eq!(decoded, Some(bits));
+            assert_eq!(decoded.map(|bits| bits.leed_internal()), Some(leed));
+            assert_eq!(decoded.map(|bits| bits.beam_internal()), Some(beam));
+        }
+
+        // Switching back to extern clears only that bit
+        let bits = DigOutBits(0xC0).with_leed_internal(false);
+        assert_eq!(bits, DigOutBits(0x80));
+        assert_eq!(bits.with_beam_internal(false), DigOutBits(0x00));
+        Ok(())
+    }
+
+    #[test]
+    fn bad_frames_map_to_errors() {
+        let status = frame(Tag::Status, 0x0D);
+        let with = |index: usize, byte: u8| {
+            let mut bytes = status;
+            bytes[index] = byte;
+            Message::from_bytes(&bytes).err()
+        };
+
+        assert!(matches!(with(0, 0x00), Some(ProtocolError::BadFraming)));
+        assert!(matches!(with(5, 0x00), Some(ProtocolError::BadFraming)));
+        assert!(matches!(
+            with(4, 0x00),
+            Some(ProtocolError::BadChecksum {
+                expected: 0x2F,
+                actual: 0x00
+            })
+        ));
+        // A frame one byte short, running into the next one
+        let short = [STX, 0x20, 0x00, 0x0D, 0x2F, STX];
+        assert!(matches!(
+            Message::from_bytes(&short),
+            Err(ProtocolError::BadFraming)
+        ));
+        let unknown = RawMessage {
+            id: 0x10,
+            msb: 0,
+            lsb: 0,
+        }
+        .to_bytes();
+        assert!(matches!(
+            Message::from_bytes(&unknown),
+            Err(ProtocolError::UnknownId(0x10))
+        ));
+    }
+
+    #[test]
+    fn error_counts_only_receive_errors() {
+        let mut counts = ErrorCounts::default();
+        counts.record(&ProtocolError::BadFraming);
+        counts.record(&ProtocolError::BadChecksum {
+            expected: 1,
+            actual: 2,
+        });
+        counts.record(&ProtocolError::UnknownId(0x10));
+        counts.record(&ProtocolError::UnknownId(0x11));
+        counts.record(&ProtocolError::ValueOutOfRange(0x10000));
+        counts.record(&ProtocolError::UnencodableTag(Tag::Unknown(0x10)));
+
+        assert_eq!(
+            counts,
+            ErrorCounts {
+                bad_checksum: 1,
+                bad_framing: 1,
+                unknown_id: 2,
+            }
+        );
+        assert_eq!(counts.total(), 4);
+    }
+
+    #[test]
+    fn decoder_resyncs_after_garbage() {
+        let status = frame(Tag::Status, 0x0D);
+        let mut decoder = FrameDecoder::new();
+
+        let mut bytes = vec![0xFF, ETX, 0x41, STX, 0x20];
+        bytes.extend_from_slice(&status);
+        assert_eq!(decoder.decode(&bytes), [status]);
+        assert_eq!(decoder.discarded(), 5);
+    }
+
+    #[test]
+    fn decoder_resyncs_after_bad_checksum() {
+        let status = frame(Tag::Status, 0x0D);
+        let mut corrupted = frame(Tag::Control(Control::SCR_SET), 1234);
+        corrupted[4] ^= 0x01;
+        let mut decoder = FrameDecoder::new();
+
+        let mut bytes = corrupted.to_vec();
+        bytes.extend_from_slice(&status);
+        assert_eq!(decoder.decode(&bytes), [status]);
+        assert_eq!(decoder.discarded(), FRAME_LEN);
+    }
+
+    #[test]
+    fn decoder_joins_frames_split_across_reads() {
+        let reply = frame(Tag::Monitor(Monitor::IFIL_MON), 0xBEEF);
+        let mut decoder = FrameDecoder::new();
+
+        assert!(decoder.decode(&reply[..1]).is_empty());
+        assert!(decoder.decode(&reply[1..4]).is_empty());
+        assert_eq!(decoder.decode(&reply[4..]), [reply]);
+        assert_eq!(decoder.discarded(), 0);
+    }
+
+    #[test]
+    fn decoder_splits_back_to_back_frames() {
+        let frames = [
+            frame(Tag::Status, 0x0D),
+            frame(Tag::Control(Control::L2_SET), 0x1234),
+            frame(Tag::Monitor(Monitor::WEH_MON), 0x0203),
+        ];
+        let bytes: Vec<u8> = frames.iter().flatten().copied().collect();
+        let mut decoder = FrameDecoder::new();
+
+        // A frame ending in the middle of a read carries over to the next one
+        let mut decoded = decoder.decode(&bytes[..8]);
+        decoded.extend(decoder.decode(&bytes[8..]));
+        assert_eq!(decoded, frames);
+        assert_eq!(decoder.discarded(), 0);
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::{Duration, Instant};
+
+// Strategy for moving a setpoint towards its target.
+// A ControlValue asks its setting what to send on every update, and tells it
+// what the controller echoed back. Values are raw DAC counts.
+pub trait Setting: Send {
+    // Sent once at startup, and used as the first target.
+    // None leaves the output alone until a target is set.
+    fn initial_value(&self) -> Option<i32> {
+        None
+    }
+
+    // Value to send now, None when nothing is due.
+    // Current is the last value echoed by the controller.
+    fn next_value(&mut self, current: i32, target: i32, domain_max: i32) -> Option<i32>;
+
+    // Called with every value echoed by the controller
+    fn read_back(&mut self, _value: i32) {}
+}
+
+// Sends the target as soon as it differs from the echoed value
+pub struct DirectSetting {
+    initial: Option<i32>,
+}
+
+impl DirectSetting {
+    pub fn new() -> Self {
+        Self { initial: None }
+    }
+
+    pub fn with_initial(initial: i32) -> Self {
+        Self {
+            initial: Some(initial),
+        }
+    }
+}
+
+impl Default for DirectSetting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Setting for DirectSetting {
+    fn initial_value(&self) -> Option<i32> {
+        self.initial
+    }
+
+    fn next_value(&mut self, current: i32, target: i32, _domain_max: i32) -> Option<i32> {
+        if target != current {
+            Some(target)
+        } else {
+            None
+        }
+    }
+}
+
+// Longest time a single ramp step covers, bounds the jump after a stall
+const MAX_STEP_TIME: Duration = Duration::from_secs(1);
+
+// Moves towards the target at a fixed rate, at most one step per interval.
+// Steps cover the time since the previous step, so the rate holds
+// however often the controller is updated.
+pub struct RampedSetting {
+    initial: Option<i32>,
+    counts_per_second: f32,
+    min_interval: Duration,
+    last_step: Option<Instant>,
+}
+
+impl RampedSetting {
+    pub fn new(counts_per_second: f32, min_interval: Duration) -> Self {
+        Self {
+            initial: None,
+            counts_per_second,
+            min_interval,
+            last_step: None,
+        }
+    }
+
+    pub fn with_initial(initial: i32, counts_per_second: f32, min_interval: Duration) -> Self {
+        Self {
+            initial: Some(initial),
+            ..Self::new(counts_per_second, min_interval)
+        }
+    }
+}
+
+impl Setting for RampedSetting {
+    fn initial_value(&self) -> Option<i32> {
+        self.initial
+    }
+
+    fn next_value(&mut self, current: i32, target: i32, domain_max: i32) -> Option<i32> {
+        if current == target {
+            self.last_step = None;
+            return None;
+        }
+
+        let Some(last_step) = self.last_step else {
+            // Starting a ramp, time is counted from here
+            self.last_step = Some(Instant::now());
+            return None;
+        };
+
+        let elapsed = last_step.elapsed();
+        if elapsed < self.min_interval {
+            return None;
+        }
+
+        let step = (self.counts_per_second * elapsed.min(MAX_STEP_TIME).as_secs_f32()) as i32;
+        if step < 1 {
+            return None; // Too slow for a full count yet
+        }
+        self.last_step = Some(Instant::now());
+
+        let value = if target < current {
+            (current - step).max(target)
+        } else {
+            (current + step).min(target)
+        };
+        Some(value.clamp(0, domain_max))
+    }
+}
+
+// Moves towards the target by at most max_step counts per send, and sends
+// no more often than the interval allows. Bounds how fast an output changes,
+// without a frame for every key press, however the target jumps.
+pub struct RateLimitedSetting {
+    initial: Option<i32>,
+    interval: Duration,
+    max_step: i32,
+    last_sent: Option<Instant>,
+}
+
+impl RateLimitedSetting {
+    pub fn new(interval: Duration, max_step: i32) -> Self {
+        Self {
+            initial: None,
+            interval,
+            max_step: max_step.max(1),
+            last_sent: None,
+        }
+    }
+
+    pub fn with_initial(initial: i32, interval: Duration, max_step: i32) -> Self {
+        Self {
+            initial: Some(initial),
+            ..Self::new(interval, max_step)
+        }
+    }
+}
+
+impl Setting for RateLimitedSetting {
+    fn initial_value(&self) -> Option<i32> {
+        self.initial
+    }
+
+    fn next_value(&mut self, current: i32, target: i32, domain_max: i32) -> Option<i32> {
+        if target == current {
+            return None;
+        }
+
+        match self.last_sent {
+            Some(last) if last.elapsed() < self.interval => None,
+            _ => {
+                self.last_sent = Some(Instant::now());
+                let value = current + (target - current).clamp(-self.max_step, self.max_step);
+                Some(value.clamp(0, domain_max))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_jumps_to_target() {
+        let mut setting = DirectSetting::with_initial(3500);
+        assert_eq!(setting.initial_value(), Some(3500));
+        assert_eq!(setting.next_value(0, 63999, 63999), Some(63999));
+        assert_eq!(setting.next_value(63999, 10, 63999), Some(10));
+        assert_eq!(setting.next_value(10, 10, 63999), None);
+    }
+
+    // Steps a ramp from current, taking every value as echoed at once
+    fn ramp(setting: &mut RampedSetting, mut current: i32, target: i32) -> Vec<i32> {
+        let mut sent = Vec::new();
+        while current != target && sent.len() < 1000 {
+            if let Some(value) = setting.next_value(current, target, 63999) {
+                sent.push(value);
+                current = value;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        sent
+    }
+
+    #[test]
+    fn ramped_steps_follow_rate() {
+        let interval = Duration::from_millis(20);
+        let mut setting = RampedSetting::new(1000.0, interval);
+
+        // The first call only starts timing
+        assert_eq!(setting.next_value(0, 10000, 63999), None);
+        std::thread::sleep(interval);
+        let step = setting.next_value(0, 10000, 63999).unwrap_or_default();
+        // 20 ms at 1000 counts/s, more if the sleep overshoots
+        assert!((20..200).contains(&step), "Step of {}", step);
+        // Not again before the interval has passed
+        assert_eq!(setting.next_value(step, 10000, 63999), None);
+    }
+
+    #[test]
+    fn ramped_reaches_target_exactly() {
+        let mut setting = RampedSetting::new(5000.0, Duration::from_millis(5));
+
+        let up = ramp(&mut setting, 0, 333);
+        assert_eq!(up.last(), Some(&333));
+        assert!(up.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", up);
+        assert!(up.len() > 2, "Jumped: {:?}", up);
+
+        let down = ramp(&mut setting, 333, 7);
+        assert_eq!(down.last(), Some(&7));
+        assert!(down.windows(2).all(|pair| pair[0] > pair[1]), "{:?}", down);
+    }
+
+    #[test]
+    fn ramped_clamps_at_domain_max() {
+        let interval = Duration::from_millis(20);
+        let mut setting = RampedSetting::new(10000.0, interval);
+
+        assert_eq!(setting.next_value(63990, 70000, 63999), None);
+        std::thread::sleep(interval);
+        assert_eq!(setting.next_value(63990, 70000, 63999), Some(63999));
+    }
+
+    #[test]
+    fn rate_limited_steps_are_bounded() {
+        let interval = Duration::from_millis(20);
+        let mut setting = RateLimitedSetting::new(interval, 100);
+
+        assert_eq!(setting.next_value(0, 1000, 63999), Some(100));
+        // Nothing more until the interval has passed
+        assert_eq!(setting.next_value(100, 1000, 63999), None);
+
+        std::thread::sleep(interval);
+        assert_eq!(setting.next_value(100, 1000, 63999), Some(200));
+
+        std::thread::sleep(interval);
+        assert_eq!(setting.next_value(950, 0, 63999), Some(850));
+
+        std::thread::sleep(interval);
+        assert_eq!(setting.next_value(850, 800, 63999), Some(800));
+        assert_eq!(setting.next_value(800, 800, 63999), None);
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Human-readable instrument settings, one "key: value [unit]" per line:
+//
+//   beam: 49.7 eV
+//   lens1 gain: 2.0
+//   suppressor: 80%
+//
+// Values without a unit are in the unit of the control, units may carry a
+// metric prefix ("7000 V" for the kV screen). Blank lines and
+// lines starting with # are skipped. Entries keep their order when written.
+
+pub const BEAM: &str = "beam";
+pub const WEHNELT: &str = "Wehn";
+pub const LENS2_OFFSET: &str = "lens2 off";
+pub const LENS2_GAIN: &str = "lens2 gain";
+pub const LENS1_OFFSET: &str = "lens1 off";
+pub const LENS1_GAIN: &str = "lens1 gain";
+pub const FILAMENT: &str = "filament";
+pub const EMISSION: &str = "emission";
+pub const SCREEN: &str = "screen";
+pub const SUPPRESSOR: &str = "suppressor";
+
+pub const KEYS: [&str; 10] = [
+    BEAM,
+    WEHNELT,
+    LENS2_OFFSET,
+    LENS2_GAIN,
+    LENS1_OFFSET,
+    LENS1_GAIN,
+    FILAMENT,
+    EMISSION,
+    SCREEN,
+    SUPPRESSOR,
+];
+
+#[derive(Debug)]
+pub enum SettingsFileError {
+    Io(io::Error),
+    Syntax {
+        line: usize,
+        message: String,
+    },
+    UnknownKey(String),
+    WrongUnit {
+        key: String,
+        expected: String,
+        found: String,
+    },
+    OutOfRange {
+        key: String,
+        value: f32,
+    },
+}
+
+impl Display for SettingsFileError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsFileError::Io(err) => write!(formatter, "{}", err),
+            SettingsFileError::Syntax { line, message } => {
+                write!(formatter, "Line {}: {}", line, message)
+            }
+            SettingsFileError::UnknownKey(key) => write!(formatter, "Unknown setting: {}", key),
+            SettingsFileError::WrongUnit {
+                key,
+                expected,
+                found,
+            } => write!(
+                formatter,
+                "{}: expected unit {}, found {}",
+                key, expected, found
+            ),
+            SettingsFileError::OutOfRange { key, value } => {
+                write!(formatter, "{}: {} is out of range", key, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SettingsFileError {}
+
+impl From<io::Error> for SettingsFileError {
+    fn from(err: io::Error) -> Self {
+        SettingsFileError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantity {
+    pub value: f32,
+    pub unit: Option<String>,
+}
+
+impl Quantity {
+    pub fn new(value: f32, unit: &str) -> Self {
+        Self {
+            value,
+            unit: Some(unit.to_string()),
+        }
+    }
+
+    pub fn unitless(value: f32) -> Self {
+        Self { value, unit: None }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let split = text
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+
+        let value = number.parse().ok()?;
+        let unit = unit.trim();
+        Some(Self {
+            value,
+            unit: (!unit.is_empty()).then(|| unit.to_string()),
+        })
+    }
+}
+
+impl Display for Quantity {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.unit.as_deref() {
+            None => write!(formatter, "{}", self.value),
+            Some("%") => write!(formatter, "{}%", self.value),
+            Some(unit) => write!(formatter, "{} {}", self.value, unit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SettingsFile {
+    entries: Vec<(String, Quantity)>,
+}
+
+impl SettingsFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SettingsFileError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SettingsFileError> {
+        let mut file = SettingsFile::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let syntax = |message: &str| SettingsFileError::Syntax {
+                line: index + 1,
+                message: message.to_string(),
+            };
+
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| syntax("Expected key: value"))?;
+            let quantity = Quantity::parse(value).ok_or_else(|| syntax("Expected a number"))?;
+            file.set(key.trim(), quantity);
+        }
+
+        Ok(file)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Quantity> {
+        self.entries
+            .iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, quantity)| quantity)
+    }
+
+    // Replaces an existing entry in place, or appends a new one
+    pub fn set(&mut self, key: &str, quantity: Quantity) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|(entry_key, _)| entry_key == key)
+        {
+            Some(entry) => entry.1 = quantity,
+            None => self.entries.push((key.to_string(), quantity)),
+        }
+    }
+
+    // Value of an entry given in the unit, or without one. None when missing.
+    pub fn value(&self, key: &str, unit: &str) -> Result<Option<f32>, SettingsFileError> {
+        let Some(quantity) = self.get(key) else {
+            return Ok(None);
+        };
+
+        match quantity.unit.as_deref() {
+            Some(found) if found != unit => Err(SettingsFileError::WrongUnit {
+                key: key.to_string(),
+                expected: unit.to_string(),
+                found: found.to_string(),
+            }),
+            _ => Ok(Some(quantity.value)),
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &Quantity)> {
+        self.entries
+            .iter()
+            .map(|(key, quantity)| (key.as_str(), quantity))
+    }
+}
+
+impl Display for SettingsFile {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, quantity) in &self.entries {
+            writeln!(formatter, "{}: {}", key, quantity)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPO_SETTINGS: &str = include_str!("../../settings");
+
+    #[test]
+    fn repo_settings_round_trip() -> Result<(), SettingsFileError> {
+        let file = SettingsFile::parse(REPO_SETTINGS)?;
+        assert_eq!(file.entries().count(), KEYS.len());
+        assert_eq!(file.get(BEAM), Some(&Quantity::new(49.7, "eV")));
+        assert_eq!(file.get(WEHNELT), Some(&Quantity::unitless(0.0)));
+        assert_eq!(file.get(SUPPRESSOR), Some(&Quantity::new(80.0, "%")));
+        assert_eq!(SettingsFile::parse(&file.to_string())?, file);
+        Ok(())
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() -> Result<(), SettingsFileError> {
+        let file = SettingsFile::parse("# Overview\n\n  beam: 50 eV\n  # screen: 7 kV\n")?;
+        assert_eq!(file.to_string(), "beam: 50 eV\n");
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_keys_are_kept() -> Result<(), SettingsFileError> {
+        // Rejected when applied to the controller, not when parsed
+        let file = SettingsFile::parse("beam: 50 eV\nfocus: 3 V\n")?;
+        assert_eq!(file.get("focus"), Some(&Quantity::new(3.0, "V")));
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_unit_is_rejected() -> Result<(), SettingsFileError> {
+        let file = SettingsFile::parse("beam: 50 V\nscreen: 7\n")?;
+        assert!(matches!(
+            file.value(BEAM, "eV"),
+            Err(SettingsFileError::WrongUnit { found, .. }) if found == "V"
+        ));
+        // Without a unit the value is in the unit of the control
+        assert_eq!(file.value(SCREEN, "kV")?, Some(7.0));
+        assert_eq!(file.value(FILAMENT, "A")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn syntax_errors_name_the_line() {
+        for (text, line) in [("beam: 50 eV\nscreen 7 kV\n", 2), ("# x\nbeam: fast\n", 2)] {
+            assert!(
+                matches!(
+                    SettingsFile::parse(text),
+                    Err(SettingsFileError::Syntax { line: found, .. }) if found == line
+                ),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn set_replaces_in_place() -> Result<(), SettingsFileError> {
+        let mut file = SettingsFile::parse("beam: 50 eV\nscreen: 7 kV\n")?;
+        file.set(BEAM, Quantity::new(100.0, "eV"));
+        file.set(FILAMENT, Quantity::new(1.8, "A"));
+        assert_eq!(
+            file.to_string(),
+            "beam: 100 eV\nscreen: 7 kV\nfilament: 1.8 A\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+use super::keepalive::WATCHDOG;
+use super::leed_controller::{monitor_range, ADC_MAX};
+use super::protocol::{Control, DigOutBits, FrameDecoder, Message, Monitor, StatusBits, Tag};
+use super::transport::{is_timeout, Transport};
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Stand-in for the NK LEED controller, answering frames like the hardware does.
+// Monitor values follow the setpoints. Filament current and emission lag behind,
+// so ramps and startup look roughly like on the real device.
+
+const DAC_COUNT: usize = 10; // DAC_1..DAC_10, $31..$3A
+
+const FILAMENT_TAU: f32 = 2.0; // Seconds
+const EMISSION_TAU: f32 = 5.0;
+const FILAMENT_AMPS_PER_COUNT: f32 = 2.7 / 63999.0;
+const EMISSION_SET_UA_PER_COUNT: f32 = 50.0 / 16959.0;
+const EMISSION_ONSET_AMPS: f32 = 1.5; // Filament current where emission starts
+const EMISSION_UA_PER_AMP: f32 = 150.0; // Available emission above onset
+const BEAM_FRACTION: f32 = 0.02; // Beam current / emission
+const BEAM_MON_VOLTS_PER_MICROAMPERE: f32 = 1.0; // Made up, the real scaling is unknown
+
+pub struct Simulator {
+    dacs: [u16; DAC_COUNT],
+    dig_out: DigOutBits,
+    pub status: StatusBits,
+    filament_amps: f32,
+    emission_ua: f32,
+    time: Duration, // Simulated time
+    last_frame: Duration,
+    watchdog_tripped: bool,
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self {
+            dacs: [0; DAC_COUNT],
+            dig_out: DigOutBits::default(),
+            status: StatusBits(
+                StatusBits::NOT_MONITOR
+                    | StatusBits::ENABLE
+                    | StatusBits::OK_15V
+                    | StatusBits::OK_15VHV,
+            ),
+            filament_amps: 0.0,
+            emission_ua: 0.0,
+            time: Duration::ZERO,
+            last_frame: Duration::ZERO,
+            watchdog_tripped: false,
+        }
+    }
+
+    pub fn set_safety_switch_open(&mut self, open: bool) {
+        self.set_status_bit(StatusBits::SAFETY_SWITCH_OPEN, open);
+    }
+
+    pub fn set_shutdown(&mut self, shutdown: bool) {
+        self.set_status_bit(StatusBits::SHUTDOWN, shutdown);
+    }
+
+    fn set_status_bit(&mut self, bit: u8, set: bool) {
+        if set {
+            self.status.0 |= bit;
+        } else {
+            self.status.0 &= !bit;
+        }
+    }
+
+    pub fn watchdog_tripped(&self) -> bool {
+        self.watchdog_tripped
+    }
+
+    pub fn dac(&self, control: Control) -> u16 {
+        self.dacs[dac_index(control)]
+    }
+
+    pub fn dig_out(&self) -> DigOutBits {
+        self.dig_out
+    }
+
+    // Reply to a valid request, None for frames the hardware would ignore
+    pub fn handle(&mut self, frame: &[u8; 6]) -> Option<[u8; 6]> {
+        let request = Message::from_bytes(frame).ok()?;
+        self.last_frame = self.time;
+        self.watchdog_tripped = false;
+
+        let value = match request.tag {
+            Tag::Status => self.status.0 as u32,
+            Tag::DigOut => {
+                self.dig_out = DigOutBits(request.value as u8);
+                self.dig_out.0 as u32
+            }
+            Tag::Control(control) => {
+                self.dacs[dac_index(control)] = request.value as u16;
+                request.value
+            }
+            Tag::Unknown(id @ 0x31..=0x3A) => {
+                self.dacs[(id - 0x31) as usize] = request.value as u16;
+                request.value
+            }
+            Tag::Monitor(monitor) => self.adc(monitor) as u32,
+            Tag::Unknown(_) => return None,
+        };
+
+        let reply = Message {
+            tag: request.tag,
+            value,
+        };
+        reply.to_bytes().ok()
+    }
+
+    pub fn advance(&mut self, dt: Duration) {
+        self.time += dt;
+
+        if !self.watchdog_tripped && self.time - self.last_frame >= WATCHDOG {
+            warn!("Simulator: no frame for {:?}, resetting outputs", WATCHDOG);
+            self.watchdog_tripped = true;
+            self.dacs = [0; DAC_COUNT];
+            self.dig_out = DigOutBits::default();
+        }
+
+        let dt = dt.as_secs_f32();
+        let filament_set = self.dac(Control::IFIL_SET1) as f32 * FILAMENT_AMPS_PER_COUNT;
+        self.filament_amps = approach(self.filament_amps, filament_set, dt, FILAMENT_TAU);
+
+        let available = (self.filament_amps - EMISSION_ONSET_AMPS).max(0.0) * EMISSION_UA_PER_AMP;
+        let regulated = self.dac(Control::EMI_SET) as f32 * EMISSION_SET_UA_PER_COUNT;
+        let emission_target = available.min(regulated);
+        self.emission_ua = approach(self.emission_ua, emission_target, dt, EMISSION_TAU);
+    }
+
+    fn adc(&self, monitor: Monitor) -> u16 {
+        // Voltage readbacks follow their setpoints, which use the same volts per count
+        let follow = |control| self.dac(control);
+        match monitor {
+            Monitor::L13_MON => follow(Control::L13_SET),
+            Monitor::L2_MON => follow(Control::L2_SET),
+            Monitor::BEAM_MON => follow(Control::BEAM_SET_INT),
+            Monitor::RET_MON => follow(Control::RET_SET_INT),
+            Monitor::SCR_MON => follow(Control::SCR_SET),
+            Monitor::WEH_MON => follow(Control::WEH_SET),
+            Monitor::IFIL_MON => to_counts(monitor, self.filament_amps),
+            Monitor::EMI_MON => to_counts(monitor, self.emission_ua),
+            Monitor::I0_MON => {
+                let beam_on = self.dac(Control::BEAM_SET_INT) > 0;
+                let beam_ua = if beam_on {
+                    self.emission_ua * BEAM_FRACTION
+                } else {
+                    0.0
+                };
+                to_counts(monitor, beam_ua * BEAM_MON_VOLTS_PER_MICROAMPERE)
+            }
+        }
+    }
+}
+
+fn dac_index(control: Control) -> usize {
+    match control {
+        Control::L2_SET => 0,
+        Control::WEH_SET => 1,
+        Control::L13_SET => 2,
+        Control::SCR_SET => 3,
+        Control::RET_SET_INT => 4,
+        Control::BEAM_SET_INT => 5,
+        Control::IFIL_SET1 => 6,
+        Control::EMI_SET => 7,
+        Control::EMI_MAX => 8,
+    }
+}
+
+fn to_counts(monitor: Monitor, value: f32) -> u16 {
+    monitor_range(monitor).to_raw(value, ADC_MAX) as u16
+}
+
+// First order lag towards target with time constant tau
+fn approach(value: f32, target: f32, dt: f32, tau: f32) -> f32 {
+    value + (target - value) * (1.0 - (-dt / tau).exp())
+}
+
+// Serves the simulator over a transport, on its own thread.
+// Reads of the transport should time out after a few milliseconds.
+pub fn serve(simulator: Arc<Mutex<Simulator>>, mut link: Box<dyn Transport>) -> JoinHandle<()> {
+    info!("Simulator serving on {}", link.describe());
+
+    thread::spawn(move || {
+        let mut decoder = FrameDecoder::new();
+        let mut last_tick = Instant::now();
+
+        loop {
+            let mut buf: [u8; 64] = [0; 64];
+            let frames = match link.read(&mut buf) {
+                Ok(0) => {
+                    info!("Simulator link closed");
+                    return;
+                }
+                Ok(count) => decoder.decode(&buf[..count]),
+                Err(err) if is_timeout(&err) => Vec::new(),
+                Err(err) => {
+                    info!("Simulator link lost: {}", err);
+                    return;
+                }
+            };
+
+            let Ok(mut simulator) = simulator.lock() else {
+                return;
+            };
+
+            let now = Instant::now();
+            simulator.advance(now.duration_since(last_tick));
+            last_tick = now;
+
+            for frame in frames {
+                if let Some(reply) = simulator.handle(&frame) {
+                    if link.write_all(&reply).is_err() {
+                        info!("Simulator link closed");
+                        return;
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::transport::ChannelTransport;
+    use std::io::{self, Read, Write};
+
+    fn request(tag: Tag, value: u32) -> [u8; 6] {
+        Message { tag, value }
+            .to_bytes()
+            .expect("Message is encodable")
+    }
+
+    fn reply(simulator: &mut Simulator, tag: Tag, value: u32) -> Option<Message> {
+        let frame = simulator.handle(&request(tag, value))?;
+        Message::from_bytes(&frame).ok()
+    }
+
+    #[test]
+    fn controls_are_echoed_and_stored() {
+        let mut simulator = Simulator::new();
+        let screen = Tag::Control(Control::SCR_SET);
+
+        assert_eq!(
+            reply(&mut simulator, screen, 1234),
+            Some(Message {
+                tag: screen,
+                value: 1234
+            })
+        );
+        assert_eq!(simulator.dac(Control::SCR_SET), 1234);
+
+        // Voltage monitors read back their setpoint
+        let monitor = reply(&mut simulator, Tag::Monitor(Monitor::SCR_MON), 0);
+        assert_eq!(monitor.map(|message| message.value), Some(1234));
+    }
+
+    #[test]
+    fn status_reports_interlocks() {
+        let mut simulator = Simulator::new();
+        simulator.set_safety_switch_open(true);
+        let status = reply(&mut simulator, Tag::Status, 0).map(|message| message.status());
+        assert_eq!(
+            status
+                .flatten()
+                .map(|status| status.0 & StatusBits::SAFETY_SWITCH_OPEN),
+            Some(StatusBits::SAFETY_SWITCH_OPEN)
+        );
+    }
+
+    #[test]
+    fn unknown_ids_are_ignored() {
+        let mut simulator = Simulator::new();
+        assert_eq!(reply(&mut simulator, Tag::Unknown(0x60), 0), None);
+    }
+
+    #[test]
+    fn watchdog_resets_outputs() {
+        let mut simulator = Simulator::new();
+        reply(&mut simulator, Tag::Control(Control::SCR_SET), 1234);
+        simulator.advance(WATCHDOG / 2);
+        assert!(!simulator.watchdog_tripped());
+
+        simulator.advance(WATCHDOG);
+        assert!(simulator.watchdog_tripped());
+        assert_eq!(simulator.dac(Control::SCR_SET), 0);
+    }
+
+    #[test]
+    fn filament_current_lags_setpoint() {
+        let mut simulator = Simulator::new();
+        reply(&mut simulator, Tag::Control(Control::IFIL_SET1), 63999);
+        simulator.advance(Duration::from_millis(500));
+        let lagging = simulator.adc(Monitor::IFIL_MON);
+
+        for _ in 0..20 {
+            reply(&mut simulator, Tag::Status, 0);
+            simulator.advance(Duration::from_millis(500));
+        }
+        let settled = simulator.adc(Monitor::IFIL_MON);
+
+        assert!(
+            lagging > 0 && lagging < settled,
+            "{} vs {}",
+            lagging,
+            settled
+        );
+    }
+
+    // Fails every read, gives up on its own after a few so a busy loop ends
+    struct FailingTransport {
+        reads: Arc<Mutex<u32>>,
+    }
+
+    impl Read for FailingTransport {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            let Ok(mut reads) = self.reads.lock() else {
+                return Ok(0);
+            };
+            *reads += 1;
+            if *reads > 100 {
+                return Ok(0);
+            }
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    impl Write for FailingTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for FailingTransport {
+        fn describe(&self) -> String {
+            "failing".to_string()
+        }
+    }
+
+    #[test]
+    fn serve_stops_on_link_error() {
+        let reads = Arc::new(Mutex::new(0));
+        let link = FailingTransport {
+            reads: reads.clone(),
+        };
+        serve(Arc::new(Mutex::new(Simulator::new())), Box::new(link))
+            .join()
+            .expect("Simulator thread ends");
+        assert_eq!(reads.lock().map(|reads| *reads).ok(), Some(1));
+    }
+
+    #[test]
+    fn serve_answers_and_stops_when_closed() -> io::Result<()> {
+        let (mut host_end, sim_end) = ChannelTransport::pair(Duration::from_millis(10));
+        let handle = serve(Arc::new(Mutex::new(Simulator::new())), Box::new(sim_end));
+
+        let status = request(Tag::Status, 0);
+        host_end.write_all(&status)?;
+        let mut buf = [0; 6];
+        host_end.read_exact(&mut buf)?;
+        assert_eq!(
+            Message::from_bytes(&buf).ok().map(|message| message.tag),
+            Some(Tag::Status)
+        );
+
+        drop(host_end);
+        handle.join().expect("Simulator thread ends");
+        Ok(())
+    }
+}
@@ -1,31 +1,485 @@
-use std::sync::mpsc;
+use super::capture::{Direction, Recorder};
+use super::keepalive::Keepalive;
+use super::protocol::FrameDecoder;
+use super::transport::{self, is_timeout, Transport};
+use log::{error, info, warn};
+use std::fmt::Display;
+use std::io;
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-const BAUD_RATE: u32 = 38400;
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting { attempt: u32 },
+    Failed, // Gave up reopening the port, the monitor thread has exited
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Connected => write!(formatter, "connected"),
+            ConnectionState::Reconnecting { attempt } => {
+                write!(formatter, "reconnecting (attempt {})", attempt)
+            }
+            ConnectionState::Failed => write!(formatter, "failed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration, // Doubled after every failed attempt
+    pub max_backoff: Duration,
+    pub max_attempts: u32,
+}
+
+impl ReconnectPolicy {
+    // Wait before an attempt, counted from 1
+    fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            max_attempts: 20,
+        }
+    }
+}
+
+pub struct MonitorHandle {
+    pub thread: JoinHandle<()>,
+    pub state: Arc<Mutex<ConnectionState>>, // Updated by the monitor thread
+    tap: Arc<Mutex<Option<Tap>>>,
+}
+
+impl MonitorHandle {
+    // Records every frame passing the link from now on.
+    // Received is the direction of frames read from the link,
+    // frames written to it are recorded in the reverse direction.
+    // The recorder can be shared with other monitors.
+    pub fn record(&self, recorder: Arc<Mutex<Recorder>>, received: Direction) {
+        if let Ok(mut tap) = self.tap.lock() {
+            *tap = Some(Tap { recorder, received });
+        }
+    }
+
+    pub fn stop_recording(&self) {
+        if let Ok(mut tap) = self.tap.lock() {
+            *tap = None;
+        }
+    }
+}
+
+struct Tap {
+    recorder: Arc<Mutex<Recorder>>,
+    received: Direction,
+}
+
+type Reopen = Box<dyn FnMut() -> io::Result<Box<dyn Transport>> + Send>;
+
+// Address is a serial port name, or "tcp://host:port".
+// The port is reopened if it fails, see ReconnectPolicy.
 pub fn monitor(
-    port_name: &str,
+    address: &str,
+    senders: Vec<mpsc::Sender<[u8; 6]>>,
+    receiver: mpsc::Receiver<[u8; 6]>,
+) -> io::Result<MonitorHandle> {
+    let link = transport::open(address, READ_TIMEOUT)?;
+    Ok(spawn(
+        link,
+        Some(reopen(address)),
+        ReconnectPolicy::default(),
+        senders,
+        receiver,
+        None,
+    ))
+}
+
+// Like monitor, but also keeps the controller watchdog satisfied
+// when nothing else is sent.
+pub fn monitor_with_keepalive(
+    address: &str,
+    senders: Vec<mpsc::Sender<[u8; 6]>>,
+    receiver: mpsc::Receiver<[u8; 6]>,
+    keepalive: Keepalive,
+) -> io::Result<MonitorHandle> {
+    let link = transport::open(address, READ_TIMEOUT)?;
+    Ok(spawn(
+        link,
+        Some(reopen(address)),
+        ReconnectPolicy::default(),
+        senders,
+        receiver,
+        Some(keepalive),
+    ))
+}
+
+// Reads of the transport should time out after a few milliseconds,
+// since sending is done from the same loop.
+// A transport cannot be reopened, so a link error is final.
+pub fn monitor_transport(
+    link: Box<dyn Transport>,
+    senders: Vec<mpsc::Sender<[u8; 6]>>,
+    receiver: mpsc::Receiver<[u8; 6]>,
+    keepalive: Option<Keepalive>,
+) -> MonitorHandle {
+    spawn(
+        link,
+        None,
+        ReconnectPolicy::default(),
+        senders,
+        receiver,
+        keepalive,
+    )
+}
+
+fn reopen(address: &str) -> Reopen {
+    let address = address.to_string();
+    Box::new(move || transport::open(&address, READ_TIMEOUT))
+}
+
+fn spawn(
+    mut link: Box<dyn Transport>,
+    mut reopen: Option<Reopen>,
+    policy: ReconnectPolicy,
     senders: Vec<mpsc::Sender<[u8; 6]>>,
     receiver: mpsc::Receiver<[u8; 6]>,
-) -> serialport::Result<JoinHandle<()>> {
-    let timeout = Duration::from_millis(10);
+    keepalive: Option<Keepalive>,
+) -> MonitorHandle {
+    let state = Arc::new(Mutex::new(ConnectionState::Connected));
+    let thread_state = state.clone();
+    let tap = Arc::new(Mutex::new(None));
+    let thread_tap = tap.clone();
 
-    let mut port = serialport::new(port_name.to_string(), BAUD_RATE)
-        .timeout(timeout)
-        .open()?;
+    let thread = thread::spawn(move || {
+        let mut pump = Pump {
+            senders,
+            receiver,
+            keepalive,
+            decoder: FrameDecoder::new(),
+            discarded: 0,
+            tap: thread_tap,
+        };
 
-    let handle = thread::spawn(move || loop {
-        let mut buf: [u8; 6] = [0; 6];
-        if port.read_exact(&mut buf).is_ok() {
-            for sender in &senders {
-                sender.send(buf).expect("Failed storing message.");
+        loop {
+            match pump.run(link.as_mut()) {
+                Stop::HungUp => {
+                    info!("Monitor of {} no longer listened to", link.describe());
+                    return;
+                }
+                Stop::LinkLost(err) => {
+                    warn!("Lost {}: {}", link.describe(), err);
+                }
             }
-        }
 
-        if let Ok(data) = receiver.try_recv() {
-            port.write_all(&data).expect("Failed sending data");
+            let reopened = match &mut reopen {
+                Some(reopen) => reconnect(reopen, &policy, &pump.receiver, &thread_state),
+                None => None,
+            };
+
+            match reopened {
+                Some(new_link) => {
+                    info!("Reconnected to {}", new_link.describe());
+                    link = new_link;
+                    pump.decoder = FrameDecoder::new();
+                    pump.discarded = 0;
+                    set_state(&thread_state, ConnectionState::Connected);
+                }
+                None => {
+                    error!("Giving up on {}", link.describe());
+                    set_state(&thread_state, ConnectionState::Failed);
+                    return;
+                }
+            }
         }
     });
-    Ok(handle)
+
+    MonitorHandle { thread, state, tap }
+}
+
+// Reopens the port with exponential backoff.
+// Frames queued meanwhile are dropped, the watchdog has reset the controller anyway.
+fn reconnect(
+    reopen: &mut Reopen,
+    policy: &ReconnectPolicy,
+    receiver: &mpsc::Receiver<[u8; 6]>,
+    state: &Arc<Mutex<ConnectionState>>,
+) -> Option<Box<dyn Transport>> {
+    for attempt in 1..=policy.max_attempts {
+        set_state(state, ConnectionState::Reconnecting { attempt });
+        thread::sleep(policy.backoff(attempt));
+
+        let dropped = receiver.try_iter().count();
+        if dropped > 0 {
+            warn!("Dropped {} frames while disconnected", dropped);
+        }
+
+        match reopen() {
+            Ok(link) => return Some(link),
+            Err(err) => info!("Reconnect attempt {} failed: {}", attempt, err),
+        }
+    }
+
+    None
+}
+
+fn set_state(state: &Arc<Mutex<ConnectionState>>, new_state: ConnectionState) {
+    if let Ok(mut state) = state.lock() {
+        *state = new_state;
+    }
+}
+
+enum Stop {
+    LinkLost(io::Error),
+    HungUp, // All listeners or the sending side are gone
+}
+
+struct Pump {
+    senders: Vec<mpsc::Sender<[u8; 6]>>,
+    receiver: mpsc::Receiver<[u8; 6]>,
+    keepalive: Option<Keepalive>,
+    decoder: FrameDecoder,
+    discarded: usize,
+    tap: Arc<Mutex<Option<Tap>>>,
+}
+
+impl Pump {
+    // Moves frames between the link and the channels until something breaks
+    fn run(&mut self, link: &mut dyn Transport) -> Stop {
+        loop {
+            let mut buf: [u8; 64] = [0; 64];
+            match link.read(&mut buf) {
+                Ok(0) => return Stop::LinkLost(io::ErrorKind::UnexpectedEof.into()),
+                Ok(count) => {
+                    for frame in self.decoder.decode(&buf[..count]) {
+                        self.capture(false, &frame);
+                        if let Some(keepalive) = &mut self.keepalive {
+                            if !keepalive.forward_reply(&frame) {
+                                continue;
+                            }
+                        }
+                        for sender in &self.senders {
+                            if sender.send(frame).is_err() {
+                                return Stop::HungUp;
+                            }
+                        }
+                    }
+
+                    if self.decoder.discarded() > self.discarded {
+                        warn!(
+                            "Discarded {} bytes while resyncing",
+                            self.decoder.discarded() - self.discarded
+                        );
+                        self.discarded = self.decoder.discarded();
+                    }
+                }
+                Err(err) if is_timeout(&err) => {}
+                Err(err) => return Stop::LinkLost(err),
+            }
+
+            match self.receiver.try_recv() {
+                Ok(data) => {
+                    if let Err(err) = link.write_all(&data) {
+                        return Stop::LinkLost(err);
+                    }
+                    self.capture(true, &data);
+                    if let Some(keepalive) = &mut self.keepalive {
+                        keepalive.frame_sent(&data, false);
+                    }
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Stop::HungUp,
+            }
+
+            if let Some(keepalive) = &mut self.keepalive {
+                if let Some(frame) = keepalive.due() {
+                    if let Err(err) = link.write_all(&frame) {
+                        return Stop::LinkLost(err);
+                    }
+                    keepalive.frame_sent(&frame, true);
+                    self.capture(true, &frame);
+                }
+            }
+        }
+    }
+
+    fn capture(&self, sent: bool, frame: &[u8; 6]) {
+        let Ok(guard) = self.tap.lock() else {
+            return;
+        };
+        let Some(tap) = guard.as_ref() else {
+            return;
+        };
+
+        let direction = if sent {
+            tap.received.reverse()
+        } else {
+            tap.received
+        };
+        let Ok(mut recorder) = tap.recorder.lock() else {
+            return;
+        };
+        if let Err(err) = recorder.record(direction, frame) {
+            error!("Failed recording frame: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::transport::ChannelTransport;
+    use std::io::Write;
+
+    const FAST: ReconnectPolicy = ReconnectPolicy {
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(4),
+        max_attempts: 5,
+    };
+    const FRAME: [u8; 6] = [0x02, 0x20, 0x00, 0x0D, 0x2F, 0x03]; // Status reply
+
+    // Reopens failing the first failures times, then with the given link.
+    // Records the published state at every attempt.
+    fn reopen_after(
+        failures: u32,
+        state: Arc<Mutex<ConnectionState>>,
+        link: Option<ChannelTransport>,
+        seen: Arc<Mutex<Vec<ConnectionState>>>,
+    ) -> Reopen {
+        let mut link = link;
+        let mut attempts = 0;
+        Box::new(move || {
+            if let (Ok(state), Ok(mut seen)) = (state.lock(), seen.lock()) {
+                seen.push(*state);
+            }
+            attempts += 1;
+            match link.take() {
+                Some(new_link) if attempts > failures => Ok(Box::new(new_link)),
+                unused => {
+                    link = unused;
+                    Err(io::ErrorKind::NotFound.into())
+                }
+            }
+        })
+    }
+
+    fn reconnecting(attempts: u32) -> Vec<ConnectionState> {
+        (1..=attempts)
+            .map(|attempt| ConnectionState::Reconnecting { attempt })
+            .collect()
+    }
+
+    fn seen(seen: &Arc<Mutex<Vec<ConnectionState>>>) -> Vec<ConnectionState> {
+        seen.lock().map(|seen| seen.clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn default_backoff_doubles_up_to_limit() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.max_attempts, 20);
+        let backoffs: Vec<u64> = (1..=8)
+            .map(|attempt| policy.backoff(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1600, 3200, 5000, 5000]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reconnect_retries_until_reopened() {
+        let state = Arc::new(Mutex::new(ConnectionState::Connected));
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let (link, _peer) = ChannelTransport::pair(READ_TIMEOUT);
+        let mut reopen = reopen_after(3, state.clone(), Some(link), attempts.clone());
+        let (_sender, receiver) = mpsc::channel();
+
+        let reopened = reconnect(&mut reopen, &FAST, &receiver, &state);
+        assert!(reopened.is_some());
+        assert_eq!(seen(&attempts), reconnecting(4));
+    }
+
+    #[test]
+    fn reconnect_gives_up_after_max_attempts() {
+        let state = Arc::new(Mutex::new(ConnectionState::Connected));
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let mut reopen = reopen_after(u32::MAX, state.clone(), None, attempts.clone());
+        let (_sender, receiver) = mpsc::channel();
+
+        assert!(reconnect(&mut reopen, &FAST, &receiver, &state).is_none());
+        assert_eq!(seen(&attempts), reconnecting(FAST.max_attempts));
+    }
+
+    #[test]
+    fn monitor_resumes_on_reopened_link() -> Result<(), mpsc::RecvTimeoutError> {
+        let (lost, lost_peer) = ChannelTransport::pair(READ_TIMEOUT);
+        drop(lost_peer);
+        let (reopened, mut reopened_peer) = ChannelTransport::pair(READ_TIMEOUT);
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        // The monitor publishes to its own state, read through the handle below
+        let unobserved = Arc::new(Mutex::new(ConnectionState::Connected));
+        let reopen = reopen_after(2, unobserved, Some(reopened), attempts.clone());
+
+        let (listener, frames) = mpsc::channel();
+        let (sender, receiver) = mpsc::channel();
+        let handle = spawn(
+            Box::new(lost),
+            Some(reopen),
+            FAST,
+            vec![listener],
+            receiver,
+            None,
+        );
+
+        reopened_peer
+            .write_all(&FRAME)
+            .map_err(|_| mpsc::RecvTimeoutError::Disconnected)?;
+        assert_eq!(frames.recv_timeout(Duration::from_secs(1))?, FRAME);
+        assert_eq!(
+            handle.state.lock().map(|state| *state).ok(),
+            Some(ConnectionState::Connected)
+        );
+        assert_eq!(seen(&attempts).len(), 3);
+
+        drop(sender);
+        assert!(handle.thread.join().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn monitor_fails_after_max_attempts() {
+        let (lost, lost_peer) = ChannelTransport::pair(READ_TIMEOUT);
+        drop(lost_peer);
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let unobserved = Arc::new(Mutex::new(ConnectionState::Connected));
+        let reopen = reopen_after(u32::MAX, unobserved, None, attempts.clone());
+
+        let (listener, _frames) = mpsc::channel();
+        let (_sender, receiver) = mpsc::channel();
+        let handle = spawn(
+            Box::new(lost),
+            Some(reopen),
+            FAST,
+            vec![listener],
+            receiver,
+            None,
+        );
+
+        assert!(handle.thread.join().is_ok());
+        assert_eq!(
+            handle.state.lock().map(|state| *state).ok(),
+            Some(ConnectionState::Failed)
+        );
+        assert_eq!(seen(&attempts).len(), FAST.max_attempts as usize);
+    }
 }
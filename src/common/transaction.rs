@@ -0,0 +1,360 @@
+use super::protocol::{Message, ProtocolError};
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// The controller replies to every valid frame with the same ID,
+// so only one request is kept in flight and the next reply is matched against it.
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    pub fn new(timeout: Duration, max_attempts: u32) -> Self {
+        Self {
+            timeout,
+            max_attempts,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(250), 3)
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Reply {
+        request: Message,
+        reply: Message,
+        latency: Duration,
+        attempts: u32,
+    },
+    TimedOut {
+        request: Message,
+        attempts: u32,
+    },
+    // Valid frame not matching the request in flight, such as a late reply
+    Unsolicited(Message),
+    Invalid([u8; 6], ProtocolError),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkStats {
+    pub sent: u32, // Frames written, retries included
+    pub replies: u32,
+    pub retries: u32,
+    pub timeouts: u32,
+    pub unsolicited: u32,
+    pub last_latency: Option<Duration>,
+    pub max_latency: Duration,
+}
+
+struct Pending {
+    request: Message,
+    bytes: [u8; 6],
+}
+
+struct InFlight {
+    pending: Pending,
+    first_sent: Instant,
+    last_sent: Instant,
+    attempts: u32,
+}
+
+pub struct Transactions {
+    sender: mpsc::Sender<[u8; 6]>,
+    receiver: mpsc::Receiver<[u8; 6]>,
+    pub policy: RetryPolicy,
+    queue: VecDeque<Pending>,
+    in_flight: Option<InFlight>,
+    stats: LinkStats,
+}
+
+impl Transactions {
+    pub fn new(
+        sender: mpsc::Sender<[u8; 6]>,
+        receiver: mpsc::Receiver<[u8; 6]>,
+        policy: RetryPolicy,
+    ) -> Self {
+        Self {
+            sender,
+            receiver,
+            policy,
+            queue: VecDeque::new(),
+            in_flight: None,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_none() && self.queue.is_empty()
+    }
+
+    // Queues a request. A queued request with the same tag is replaced,
+    // so only the latest value for each ID is sent.
+    pub fn request(&mut self, request: Message) -> Result<(), ProtocolError> {
+        let bytes = request.to_bytes()?;
+
+        if let Some(in_flight) = &self.in_flight {
+            if in_flight.pending.request == request {
+                return Ok(());
+            }
+        }
+
+        match self.queue.iter_mut().find(|p| p.request.tag == request.tag) {
+            Some(pending) => *pending = Pending { request, bytes },
+            None => self.queue.push_back(Pending { request, bytes }),
+        }
+
+        Ok(())
+    }
+
+    // Drops the requests not sent yet, the one in flight is still answered
+    pub fn drop_queued(&mut self) -> usize {
+        let count = self.queue.len();
+        self.queue.clear();
+        count
+    }
+
+    // Handles received frames, retries or gives up on the request in flight,
+    // and sends the next queued request once the link is free.
+    pub fn poll(&mut self) -> Result<Vec<Event>, mpsc::SendError<[u8; 6]>> {
+        let mut events = Vec::new();
+
+        while let Ok(buf) = self.receiver.try_recv() {
+            match Message::from_bytes(&buf) {
+                Ok(reply) => events.push(self.match_reply(reply)),
+                Err(err) => events.push(Event::Invalid(buf, err)),
+            }
+        }
+
+        if let Some(event) = self.check_timeout()? {
+            events.push(event);
+        }
+
+        if self.in_flight.is_none() {
+            if let Some(pending) = self.queue.pop_front() {
+                self.sender.send(pending.bytes)?;
+                self.stats.sent += 1;
+                let now = Instant::now();
+                self.in_flight = Some(InFlight {
+                    pending,
+                    first_sent: now,
+                    last_sent: now,
+                    attempts: 1,
+                });
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn match_reply(&mut self, reply: Message) -> Event {
+        match self.in_flight.take() {
+            Some(in_flight) if in_flight.pending.request.tag == reply.tag => {
+                let latency = in_flight.first_sent.elapsed();
+                self.stats.replies += 1;
+                self.stats.last_latency = Some(latency);
+                self.stats.max_latency = self.stats.max_latency.max(latency);
+                Event::Reply {
+                    request: in_flight.pending.request,
+                    reply,
+                    latency,
+                    attempts: in_flight.attempts,
+                }
+            }
+            in_flight => {
+                self.in_flight = in_flight;
+                self.stats.unsolicited += 1;
+                Event::Unsolicited(reply)
+            }
+        }
+    }
+
+    fn check_timeout(&mut self) -> Result<Option<Event>, mpsc::SendError<[u8; 6]>> {
+        let Some(in_flight) = &mut self.in_flight else {
+            return Ok(None);
+        };
+
+        if in_flight.last_sent.elapsed() < self.policy.timeout {
+            return Ok(None);
+        }
+
+        if in_flight.attempts < self.policy.max_attempts {
+            self.sender.send(in_flight.pending.bytes)?;
+            self.stats.sent += 1;
+            in_flight.last_sent = Instant::now();
+            in_flight.attempts += 1;
+            self.stats.retries += 1;
+            Ok(None)
+        } else {
+            let attempts = in_flight.attempts;
+            let request = in_flight.pending.request;
+            self.in_flight = None;
+            self.stats.timeouts += 1;
+            Ok(Some(Event::TimedOut { request, attempts }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::protocol::{Control, Tag};
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_millis(20);
+
+    struct Link {
+        transactions: Transactions,
+        port: mpsc::Receiver<[u8; 6]>,  // Frames sent to the controller
+        replies: mpsc::Sender<[u8; 6]>, // Frames from the controller
+    }
+
+    impl Link {
+        fn new() -> Self {
+            let (to_port, port) = mpsc::channel();
+            let (replies, from_port) = mpsc::channel();
+            Self {
+                transactions: Transactions::new(to_port, from_port, RetryPolicy::new(TIMEOUT, 3)),
+                port,
+                replies,
+            }
+        }
+
+        fn sent(&self) -> Vec<Message> {
+            self.port
+                .try_iter()
+                .map(|frame| Message::from_bytes(&frame).expect("Valid frame"))
+                .collect()
+        }
+
+        fn reply(&self, message: Message) {
+            let frame = message.to_bytes().expect("Message is encodable");
+            self.replies.send(frame).expect("Link is open");
+        }
+
+        fn poll(&mut self) -> Vec<Event> {
+            self.transactions.poll().expect("Link is open")
+        }
+    }
+
+    fn control(control: Control, value: u32) -> Message {
+        Message {
+            tag: Tag::Control(control),
+            value,
+        }
+    }
+
+    #[test]
+    fn reply_matches_request_in_flight() {
+        let mut link = Link::new();
+        let screen = control(Control::SCR_SET, 100);
+        let filament = control(Control::IFIL_SET1, 200);
+        link.transactions.request(screen).expect("Valid request");
+        link.transactions.request(filament).expect("Valid request");
+
+        assert!(link.poll().is_empty());
+        assert_eq!(link.sent(), [screen]);
+
+        // Only one request is in flight, a reply for another ID is unsolicited
+        link.reply(filament);
+        assert!(matches!(link.poll()[..], [Event::Unsolicited(_)]));
+        assert!(link.sent().is_empty());
+
+        link.reply(screen);
+        assert!(matches!(
+            link.poll()[..],
+            [Event::Reply { request, attempts: 1, .. }] if request == screen
+        ));
+        assert_eq!(link.sent(), [filament]);
+
+        link.reply(filament);
+        link.poll();
+        assert!(link.transactions.is_idle());
+
+        let stats = link.transactions.stats();
+        assert_eq!((stats.replies, stats.unsolicited), (2, 1));
+    }
+
+    #[test]
+    fn queued_request_is_replaced() {
+        let mut link = Link::new();
+        link.transactions
+            .request(control(Control::L2_SET, 1))
+            .expect("Valid request");
+        link.transactions
+            .request(control(Control::SCR_SET, 1))
+            .expect("Valid request");
+        link.transactions
+            .request(control(Control::SCR_SET, 2))
+            .expect("Valid request");
+
+        link.poll();
+        link.reply(control(Control::L2_SET, 1));
+        link.poll();
+        assert_eq!(
+            link.sent(),
+            [control(Control::L2_SET, 1), control(Control::SCR_SET, 2)]
+        );
+    }
+
+    #[test]
+    fn request_is_retried_after_timeout() {
+        let mut link = Link::new();
+        let screen = control(Control::SCR_SET, 100);
+        link.transactions.request(screen).expect("Valid request");
+        link.poll();
+
+        thread::sleep(TIMEOUT);
+        assert!(link.poll().is_empty());
+        assert_eq!(link.sent(), [screen, screen]);
+
+        link.reply(screen);
+        assert!(matches!(
+            link.poll()[..],
+            [Event::Reply { attempts: 2, .. }]
+        ));
+        assert_eq!(link.transactions.stats().retries, 1);
+    }
+
+    #[test]
+    fn request_times_out_after_max_attempts() {
+        let mut link = Link::new();
+        let screen = control(Control::SCR_SET, 100);
+        let filament = control(Control::IFIL_SET1, 200);
+        link.transactions.request(screen).expect("Valid request");
+        link.transactions.request(filament).expect("Valid request");
+        link.poll();
+
+        let mut events = Vec::new();
+        for _ in 0..3 {
+            thread::sleep(TIMEOUT);
+            events.extend(link.poll());
+        }
+
+        assert!(matches!(
+            events[..],
+            [Event::TimedOut { request, attempts: 3 }] if request == screen
+        ));
+        // The next request goes out once the link is free
+        assert_eq!(link.sent(), [screen, screen, screen, filament]);
+
+        let stats = link.transactions.stats();
+        assert_eq!((stats.retries, stats.timeouts), (2, 1));
+
+        // A late reply no longer matches
+        link.reply(screen);
+        assert!(matches!(link.poll()[..], [Event::Unsolicited(_)]));
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+const BAUD_RATE: u32 = 38400;
+const TCP_PREFIX: &str = "tcp://";
+// Per resolved address, the transport timeout is meant for reads
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Byte link to a device. Reads give up after the transport's timeout,
+// returning a TimedOut or WouldBlock error when no data arrived.
+pub trait Transport: Read + Write + Send {
+    fn describe(&self) -> String;
+}
+
+// Read error meaning only that no data arrived in time
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+// Opens a transport from an address.
+// "tcp://host:port" connects over TCP, anything else is taken as a serial port.
+pub fn open(address: &str, timeout: Duration) -> io::Result<Box<dyn Transport>> {
+    match address.strip_prefix(TCP_PREFIX) {
+        Some(host) => Ok(Box::new(TcpTransport::connect(host, timeout)?)),
+        None => Ok(Box::new(SerialTransport::open(address, timeout)?)),
+    }
+}
+
+pub struct SerialTransport {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+impl SerialTransport {
+    pub fn open(port_name: &str, timeout: Duration) -> serialport::Result<Self> {
+        let port = serialport::new(port_name.to_string(), BAUD_RATE)
+            .timeout(timeout)
+            .open()?;
+        Ok(Self { port })
+    }
+
+    pub fn from_port(port: Box<dyn serialport::SerialPort>) -> Self {
+        Self { port }
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl Transport for SerialTransport {
+    fn describe(&self) -> String {
+        self.port.name().unwrap_or_else(|| "serial".to_string())
+    }
+}
+
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    // Tries each address the host resolves to, returns the last error if none connects
+    pub fn connect(host: &str, timeout: Duration) -> io::Result<Self> {
+        let mut last_err = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolves to no address", host),
+        );
+        for addr in host.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Self::from_stream(stream, timeout),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    pub fn from_stream(stream: TcpStream, timeout: Duration) -> io::Result<Self> {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn describe(&self) -> String {
+        match self.stream.peer_addr() {
+            Ok(addr) => format!("{}{}", TCP_PREFIX, addr),
+            Err(_) => "tcp".to_string(),
+        }
+    }
+}
+
+// In-process link, for running against simulators and in tests
+pub struct ChannelTransport {
+    sender: mpsc::Sender<Vec<u8>>,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    timeout: Duration,
+}
+
+impl ChannelTransport {
+    // Two connected ends, what is written to one is read from the other
+    pub fn pair(timeout: Duration) -> (ChannelTransport, ChannelTransport) {
+        let (a_send, b_recv) = mpsc::channel();
+        let (b_send, a_recv) = mpsc::channel();
+
+        (
+            ChannelTransport {
+                sender: a_send,
+                receiver: a_recv,
+                pending: VecDeque::new(),
+                timeout,
+            },
+            ChannelTransport {
+                sender: b_send,
+                receiver: b_recv,
+                pending: VecDeque::new(),
+                timeout,
+            },
+        )
+    }
+}
+
+impl Read for ChannelTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.receiver.recv_timeout(self.timeout) {
+                Ok(bytes) => self.pending.extend(bytes),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut))
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        while let Ok(bytes) = self.receiver.try_recv() {
+            self.pending.extend(bytes);
+        }
+
+        let count = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..count)) {
+            *dst = src;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for ChannelTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn describe(&self) -> String {
+        "channel".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const FRAME: [u8; 6] = [0x02, 0x20, 0x00, 0x0D, 0x2F, 0x03];
+
+    #[test]
+    fn tcp_round_trip() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = format!("{}{}", TCP_PREFIX, listener.local_addr()?);
+        let echo = thread::spawn(move || -> io::Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut frame = [0; 6];
+            stream.read_exact(&mut frame)?;
+            stream.write_all(&frame)
+        });
+
+        let mut link = open(&address, Duration::from_millis(20))?;
+        assert_eq!(link.describe(), address);
+
+        // Nothing sent yet, the read times out
+        let mut buf = [0; 6];
+        let err = link.read(&mut buf).err();
+        assert!(err.is_some_and(|err| is_timeout(&err)));
+
+        link.write_all(&FRAME)?;
+        let mut echoed = Vec::new();
+        while echoed.len() < FRAME.len() {
+            match link.read(&mut buf) {
+                Ok(0) => break,
+                Ok(count) => echoed.extend_from_slice(&buf[..count]),
+                Err(err) if is_timeout(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        assert_eq!(echoed, FRAME);
+        echo.join()
+            .map_err(|_| io::Error::other("Echo thread panicked"))?
+    }
+
+    #[test]
+    fn tcp_connect_fails_without_listener() -> io::Result<()> {
+        let address = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        // The listener is closed again, nothing accepts on the port
+        assert!(TcpTransport::connect(&address.to_string(), Duration::from_millis(20)).is_err());
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
+use crate::common::transport::{self, Transport};
 use log::{error, info};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use serialport::SerialPort;
 use std::{
     error::Error,
     io::{self, BufRead, BufReader, Write},
@@ -13,8 +13,6 @@ use std::{
 // Talks to a server over serial, controlling motors for scanning.
 // Server currently implemented in motors_server.py
 
-const BAUD_RATE: u32 = 38400;
-
 const DEFAULT_STEP_SIZE: f32 = 0.2;
 const DEFAULT_AREA: AreaConf = AreaConf {
     // center: (-0.8, 5.5, 58.25), // Lower slot
@@ -79,15 +77,18 @@ pub struct MotorsClient {
 }
 
 impl MotorsClient {
-    pub fn new(port_name: &str, callbacks: Callbacks) -> Result<Self, io::Error> {
+    // Address is a serial port name, or "tcp://host:port"
+    pub fn new(address: &str, callbacks: Callbacks) -> Result<Self, io::Error> {
+        let timeout = Duration::from_millis(100);
+        let port = transport::open(address, timeout)?;
+        Ok(Self::with_transport(port, callbacks))
+    }
+
+    // Reads of the transport should time out, so commands get sent
+    pub fn with_transport(mut port: Box<dyn Transport>, callbacks: Callbacks) -> Self {
         let (msg_writer, msg_receiver) = mpsc::channel();
         let (cmd_writer, cmd_receiver) = mpsc::channel();
 
-        let timeout = Duration::from_millis(100);
-        let mut port = serialport::new(port_name.to_string(), BAUD_RATE)
-            .timeout(timeout)
-            .open()?;
-
         thread::spawn(move || loop {
             // info!("Requesting motor position");
 
@@ -176,13 +177,13 @@ impl MotorsClient {
             thread::sleep(Duration::from_millis(1));
         });
 
-        Ok(Self {
+        Self {
             last_pos: (0, 0),
             receiver: msg_receiver,
             sender: cmd_writer,
             callbacks,
             step_size: DEFAULT_STEP_SIZE,
-        })
+        }
     }
 
     pub fn get_last_pos(&self) -> (i32, i32) {
@@ -256,7 +257,7 @@ impl MotorsClient {
     }
 }
 
-fn _request_pos(port: &mut Box<dyn SerialPort>) -> Result<(), Box<dyn Error>> {
+fn request_pos(port: &mut dyn Transport) -> Result<(), Box<dyn Error>> {
     let get_pos_msg = json!({
         "tag": "get_pos",
     });
@@ -265,3 +266,21 @@ fn _request_pos(port: &mut Box<dyn SerialPort>) -> Result<(), Box<dyn Error>> {
     port.write_all("\n".as_bytes())?;
     Ok(())
 }
+
+// Checks whether a motors server is at the other end, by asking for its position
+pub fn probe(port: &mut dyn Transport) -> bool {
+    if request_pos(port).is_err() {
+        return false;
+    }
+
+    let mut reader = BufReader::new(port);
+    let mut response = String::new();
+    if reader.read_line(&mut response).is_err() {
+        return false;
+    }
+
+    matches!(
+        serde_json::from_str(response.as_str()),
+        Ok(Msg::CurrentPos { .. })
+    )
+}